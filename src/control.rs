@@ -0,0 +1,127 @@
+use std::fs::{DirBuilder, Permissions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use crossbeam_channel::{select, Receiver, Sender};
+use serde_json::json;
+
+use crate::events::subscribe;
+use crate::types::{ControlRequest, MainEvent};
+
+/// Take commands on a Unix socket and pass them on to the state thread, a thread per client.
+pub fn control_task(path: String, main_sender: Sender<MainEvent>) -> Result<(), anyhow::Error> {
+	// Left behind by an earlier run, anything else there is left alone
+	match std::fs::symlink_metadata(&path) {
+		Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(&path)?,
+		Ok(_) => bail!("{} is there already and isn't a socket", path),
+		Err(_) => {}
+	}
+	let listener = bind_private(Path::new(&path)).map_err(|e| anyhow!("{}: {}", path, e))?;
+
+	for stream in listener.incoming() {
+		let stream = match stream {
+			Ok(stream) => stream,
+			Err(e) => {
+				println!("Control: {:?}", e);
+				continue;
+			}
+		};
+		let main_sender = main_sender.clone();
+		std::thread::spawn(move || {
+			serve(stream, main_sender).unwrap_or_else(|e| {
+				println!("Control client: {:?}", e);
+			})
+		});
+	}
+	Ok(())
+}
+
+/// Whoever can connect can type anything, so the socket is made in a directory only we can get into and moved
+/// into place once nobody else can connect to it.
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+	let name = path.file_name().ok_or(io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?;
+	let dir = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+	DirBuilder::new().mode(0o700).create(&dir)?;
+	let private = dir.join(name);
+	let bind = || -> io::Result<UnixListener> {
+		let listener = UnixListener::bind(&private)?;
+		std::fs::set_permissions(&private, Permissions::from_mode(0o600))?;
+		std::fs::rename(&private, path)?;
+		Ok(listener)
+	};
+	let listener = bind();
+	if listener.is_err() {
+		let _ = std::fs::remove_file(&private);
+	}
+	std::fs::remove_dir(&dir)?;
+	listener
+}
+
+/// Answer each line with `{"ok": true, "result": ...}` or `{"ok": false, "error": "..."}`.
+fn serve(stream: UnixStream, main_sender: Sender<MainEvent>) -> Result<(), anyhow::Error> {
+	let mut writer = stream.try_clone()?;
+	let mut lines = BufReader::new(stream).lines();
+	let events = loop {
+		let Some(line) = lines.next() else { return Ok(()) };
+		let line = line?;
+		if line.trim().is_empty() {
+			continue;
+		}
+		let (result, events) = match serde_json::from_str::<ControlRequest>(&line) {
+			Ok(ControlRequest::Subscribe) => {
+				// Subscribed before asking, so nothing that happens in between is missed
+				let events = subscribe();
+				(ask(&main_sender, ControlRequest::Query)?, Some(events))
+			}
+			Ok(request) => (ask(&main_sender, request)?, None),
+			Err(e) => (Err(e.to_string()), None),
+		};
+		let response = match result {
+			Ok(result) => json!({ "ok": true, "result": result }),
+			Err(error) => json!({ "ok": false, "error": error }),
+		};
+		writeln!(writer, "{}", response)?;
+		if let Some(events) = events {
+			break events;
+		}
+	};
+
+	// A subscriber says it's done by going away, which a quiet stream would only notice on the next event
+	let (closed_sender, closed) = crossbeam_channel::bounded::<()>(0);
+	std::thread::spawn(move || {
+		for _ in lines.map_while(Result::ok) {}
+		drop(closed_sender);
+	});
+	stream_events(&mut writer, events, closed);
+	let _ = writer.shutdown(Shutdown::Both);
+	Ok(())
+}
+
+/// Until the subscriber is gone or falls too far behind.
+fn stream_events(writer: &mut UnixStream, events: Receiver<String>, closed: Receiver<()>) {
+	loop {
+		select! {
+			recv(events) -> event => {
+				let Ok(event) = event else { return };
+				if writeln!(writer, "{}", event).is_err() {
+					return;
+				}
+			}
+			recv(closed) -> _ => return,
+		}
+	}
+}
+
+/// Have the state thread carry out a request, the outer error means it's gone.
+fn ask(
+	main_sender: &Sender<MainEvent>,
+	request: ControlRequest,
+) -> Result<Result<serde_json::Value, String>, anyhow::Error> {
+	let (reply, result) = crossbeam_channel::bounded(1);
+	main_sender.send(MainEvent::Control(request, reply))?;
+	Ok(result.recv().unwrap_or(Err("Shutting down".to_string())))
+}
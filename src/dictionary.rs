@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read_to_string, write};
+use std::hash::Hash;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+
+use crate::types::{Button, PredictionConfig};
+
+/// Words and how often they're used, for working out what a swipe meant.
+#[derive(Debug, Default)]
+pub struct Dictionary {
+	/// Most used first
+	words: Vec<(String, u64)>,
+}
+
+impl Dictionary {
+	/// One word per line, optionally followed by how often it's used. Without counts, earlier words rank higher.
+	pub fn load(path: &str) -> Result<Self, anyhow::Error> {
+		let text = read_to_string(path)?;
+		let lines: Vec<&str> = text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')).collect();
+		let mut words = Vec::with_capacity(lines.len());
+		for (i, line) in lines.iter().enumerate() {
+			let mut parts = line.split_whitespace();
+			let word = parts.next().unwrap().to_string();
+			let count = match parts.next() {
+				Some(count) => count.parse().map_err(|_| anyhow!("{}: bad count on line '{}'", path, line))?,
+				None => (lines.len() - i) as u64,
+			};
+			words.push((word, count));
+		}
+		words.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+		Ok(Self { words })
+	}
+
+	/// Words starting with `prefix` and longer than it, most used first.
+	pub fn complete(&self, prefix: &str, limit: usize) -> Vec<String> {
+		let prefix = prefix.to_lowercase();
+		self.words
+			.iter()
+			.filter(|(word, _)| word.len() > prefix.len() && word.to_lowercase().starts_with(&prefix))
+			.take(limit)
+			.map(|(word, _)| word.clone())
+			.collect()
+	}
+
+	/// Count `count` more uses of `word`.
+	pub fn add(&mut self, word: &str, count: u64) {
+		let mut pos = match self.words.iter().position(|(other, _)| other == word) {
+			Some(pos) => {
+				self.words[pos].1 += count;
+				pos
+			}
+			None => {
+				self.words.push((word.to_string(), count));
+				self.words.len() - 1
+			}
+		};
+		while pos > 0 && self.words[pos - 1].1 < self.words[pos].1 {
+			self.words.swap(pos - 1, pos);
+			pos -= 1;
+		}
+	}
+
+	/// Write the words in the format `load` reads.
+	pub fn save(&self, path: &str) -> io::Result<()> {
+		if let Some(dir) = Path::new(path).parent() {
+			create_dir_all(dir)?;
+		}
+		let text: String = self.words.iter().map(|(word, count)| format!("{} {}\n", word, count)).collect();
+		write(path, text)
+	}
+
+	/// Words that can be swiped along `path`, most used first. `keys` says where each letter is.
+	/// The path has to start and end on a word's first and last letters and pass the rest in order.
+	pub fn swipe_matches<K: Copy + Eq + Hash>(&self, keys: &HashMap<char, K>, path: &[K], limit: usize) -> Vec<String> {
+		self.words
+			.iter()
+			.filter(|(word, _)| {
+				let mut seq: Vec<K> = Vec::new();
+				for c in word.chars().flat_map(char::to_lowercase) {
+					let Some(key) = keys.get(&c) else { return false };
+					if seq.last() != Some(key) {
+						seq.push(*key);
+					}
+				}
+				let mut rest = path.iter();
+				seq.first() == path.first()
+					&& seq.last() == path.last()
+					&& seq.iter().all(|key| rest.any(|other| other == key))
+			})
+			.take(limit)
+			.map(|(word, _)| word.clone())
+			.collect()
+	}
+}
+
+/// Suggests how to finish the word being typed, and learns the words that get typed.
+#[derive(Debug, Default)]
+pub struct Predictor {
+	/// The configured word list plus everything learned
+	words: Dictionary,
+	learned: Dictionary,
+	learned_path: Option<String>,
+	/// When to write out what was learned, if there's anything new
+	save_at: Option<Instant>,
+}
+
+/// How long learned words wait to be written, so typing doesn't rewrite the file every word.
+const SAVE_DELAY: Duration = Duration::from_secs(30);
+
+impl Predictor {
+	pub fn new(config: &PredictionConfig) -> Self {
+		let mut predictor = Self { learned_path: config.user_dictionary.clone(), ..Self::default() };
+		if let Some(path) = &config.dictionary {
+			match Dictionary::load(path) {
+				Ok(words) => predictor.words = words,
+				Err(e) => println!("Dictionary {}: {:?}", path, e),
+			}
+		}
+		if let Some(path) = &config.user_dictionary {
+			// Nothing learned yet is fine
+			match Dictionary::load(path) {
+				Ok(learned) => predictor.learned = learned,
+				Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => {}
+				Err(e) => println!("Dictionary {}: {:?}", path, e),
+			}
+		}
+		for (word, count) in &predictor.learned.words {
+			predictor.words.add(word, *count);
+		}
+		predictor
+	}
+
+	pub fn complete(&self, prefix: &str, limit: usize) -> Vec<String> {
+		self.words.complete(prefix, limit)
+	}
+
+	pub fn learn(&mut self, word: &str) {
+		let word = word.to_lowercase();
+		self.words.add(&word, 1);
+		self.learned.add(&word, 1);
+		if self.learned_path.is_some() {
+			self.save_at.get_or_insert_with(|| Instant::now() + SAVE_DELAY);
+		}
+	}
+
+	pub fn save_at(&self) -> Option<Instant> {
+		self.save_at
+	}
+
+	/// Write out the learned words if any are new.
+	pub fn save(&mut self) {
+		let (Some(path), Some(_)) = (&self.learned_path, self.save_at.take()) else { return };
+		if let Err(e) = self.learned.save(path) {
+			println!("Dictionary {}: {:?}", path, e);
+		}
+	}
+}
+
+/// What to type for chords of buttons.
+#[derive(Debug, Default)]
+pub struct ChordDictionary {
+	/// Keys are sorted
+	chords: HashMap<Vec<Button>, String>,
+}
+
+impl ChordDictionary {
+	/// One chord per line, buttons joined with `+` and then the text, e.g. `A+X+LeftShoulder hello`.
+	pub fn load(path: &str) -> Result<Self, anyhow::Error> {
+		let text = read_to_string(path)?;
+		let mut chords = HashMap::new();
+		for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+			let (chord, output) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+			let buttons = chord
+				.split('+')
+				.map(|name| Button::from_str(name).map_err(|_| anyhow!("{}: unknown button '{}'", path, name)))
+				.collect::<Result<Vec<_>, _>>()?;
+			chords.insert(Self::key(&buttons), output.trim().to_string());
+		}
+		Ok(Self { chords })
+	}
+
+	pub fn lookup(&self, buttons: &[Button]) -> Option<&String> {
+		self.chords.get(&Self::key(buttons))
+	}
+
+	fn key(buttons: &[Button]) -> Vec<Button> {
+		let mut key = buttons.to_vec();
+		key.sort_by_key(|btn| *btn as usize);
+		key.dedup();
+		key
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn temp_path(name: &str) -> String {
+		let path = std::env::temp_dir().join(format!("tamepad-{}-{}", std::process::id(), name));
+		path.to_str().unwrap().to_string()
+	}
+
+	fn dictionary(words: &[(&str, u64)]) -> Dictionary {
+		let mut dictionary = Dictionary::default();
+		for (word, count) in words {
+			dictionary.add(word, *count);
+		}
+		dictionary
+	}
+
+	#[test]
+	fn load_ranks_by_count_or_order() {
+		let path = temp_path("load");
+		write(&path, "# comment\nthe\nof 100\n\nand\n").unwrap();
+		let loaded = Dictionary::load(&path);
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(loaded.unwrap().words, [("of".to_string(), 100), ("the".to_string(), 3), ("and".to_string(), 1)]);
+	}
+
+	#[test]
+	fn add_moves_words_up() {
+		let mut words = dictionary(&[("hello", 3), ("help", 2), ("helm", 1)]);
+		assert_eq!(words.complete("He", 2), ["hello", "help"]);
+		words.add("helm", 5);
+		assert_eq!(words.complete("hel", 3), ["helm", "hello", "help"]);
+		// The word itself is nothing to suggest
+		assert_eq!(words.complete("help", 3), Vec::<String>::new());
+	}
+
+	#[test]
+	fn save_and_load_again() {
+		let path = temp_path("save");
+		dictionary(&[("one", 2), ("two", 1)]).save(&path).unwrap();
+		let loaded = Dictionary::load(&path);
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(loaded.unwrap().words, [("one".to_string(), 2), ("two".to_string(), 1)]);
+	}
+
+	#[test]
+	fn swipe_needs_first_and_last_letter() {
+		let keys: HashMap<char, u8> = "abcdefghijklmnopqrstuvwxyz".chars().zip(0..).collect();
+		let words = dictionary(&[("cat", 3), ("cart", 2), ("coat", 1)]);
+		let path: Vec<u8> = "cxoart".chars().map(|c| keys[&c]).collect();
+		assert_eq!(words.swipe_matches(&keys, &path, 5), ["cat", "cart", "coat"]);
+		let path: Vec<u8> = "cxar".chars().map(|c| keys[&c]).collect();
+		assert_eq!(words.swipe_matches(&keys, &path, 5), Vec::<String>::new());
+	}
+
+	#[test]
+	fn predictor_saves_learned_words_later() {
+		let path = temp_path("learned");
+		let config = PredictionConfig {
+			dictionary: None,
+			user_dictionary: Some(path.clone()),
+			suggestions: 3,
+			suffix: " ".to_string(),
+			x: 0.,
+			y: 0.,
+		};
+		let mut predictor = Predictor::new(&config);
+		predictor.learn("Rust");
+		predictor.learn("rusty");
+		predictor.learn("rusty");
+		assert_eq!(predictor.complete("ru", 3), ["rusty", "rust"]);
+		assert!(predictor.save_at().is_some());
+		assert!(!Path::new(&path).exists());
+
+		predictor.save();
+		assert!(predictor.save_at().is_none());
+		let learned = Dictionary::load(&path);
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(learned.unwrap().words, [("rusty".to_string(), 2), ("rust".to_string(), 1)]);
+	}
+
+	#[test]
+	fn chords_ignore_order() {
+		let path = temp_path("chords");
+		write(&path, "A+B hello\nX world\n").unwrap();
+		let chords = ChordDictionary::load(&path);
+		std::fs::remove_file(&path).unwrap();
+		let chords = chords.unwrap();
+		assert_eq!(chords.lookup(&[Button::B, Button::A]).map(String::as_str), Some("hello"));
+		assert_eq!(chords.lookup(&[Button::X]).map(String::as_str), Some("world"));
+		assert_eq!(chords.lookup(&[Button::A]), None);
+	}
+}
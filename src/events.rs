@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crossbeam_channel::{Receiver, Sender};
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::types::{Button, Key, StateMapping};
+
+/// Something that happened, for whoever subscribed on the control socket.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum StateEvent {
+	/// Active overlays from the bottom up
+	#[serde(rename_all = "camelCase")]
+	Overlays { overlays: Vec<String>, base_overlay: String },
+	/// What a button ended up doing after combos, sequences and steno had their say
+	Button { button: Button, down: bool, overlay: Option<String>, map: Option<StateMapping> },
+	Combo { combo: String, down: bool, overlay: Option<String>, map: Option<StateMapping> },
+	/// A key or button on one of the output devices
+	Key { key: Key, down: bool },
+	/// The ring and sector a stick is in, if any
+	Sector { stick: usize, sector: Option<(usize, usize)> },
+}
+
+/// How many events a subscriber may fall behind before it's dropped.
+const BACKLOG: usize = 1024;
+
+enum Message {
+	Subscribe(Sender<String>),
+	/// With when it happened, in ms since the epoch
+	Event(StateEvent, u64),
+}
+
+lazy_static! {
+	// Events are serialized and handed out on a thread of their own, so publishing costs the state and
+	// actions threads no more than a send.
+	static ref DISPATCHER: Sender<Message> = {
+		let (sender, receiver) = crossbeam_channel::unbounded();
+		std::thread::spawn(move || dispatch(receiver));
+		sender
+	};
+}
+
+/// Subscribers the dispatcher has or is about to get, so nothing is sent its way while there are none.
+static SUBSCRIBERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Every event from now on, as lines of JSON.
+pub fn subscribe() -> Receiver<String> {
+	let (sender, receiver) = crossbeam_channel::bounded(BACKLOG);
+	SUBSCRIBERS.fetch_add(1, Ordering::SeqCst);
+	DISPATCHER.send(Message::Subscribe(sender)).unwrap();
+	receiver
+}
+
+pub fn publish(event: StateEvent) {
+	if SUBSCRIBERS.load(Ordering::SeqCst) == 0 {
+		return;
+	}
+	let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+	DISPATCHER.send(Message::Event(event, time.as_millis() as u64)).unwrap();
+}
+
+fn dispatch(receiver: Receiver<Message>) {
+	let mut subscribers: Vec<Sender<String>> = Vec::new();
+	for message in receiver {
+		match message {
+			Message::Subscribe(subscriber) => subscribers.push(subscriber),
+			Message::Event(event, time) => {
+				let mut line = serde_json::to_value(event).unwrap();
+				line["time"] = time.into();
+				let line = line.to_string();
+				// Whoever is gone or can't keep up is dropped
+				let before = subscribers.len();
+				subscribers.retain(|subscriber| subscriber.try_send(line.clone()).is_ok());
+				SUBSCRIBERS.fetch_sub(before - subscribers.len(), Ordering::SeqCst);
+			}
+		}
+	}
+}
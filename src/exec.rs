@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender};
+
+use crate::types::ExecMapping;
+
+/// Runs commands for the actions thread, remembering when each one last ran for debouncing.
+pub struct Launcher {
+	last_run: HashMap<(String, Vec<String>), Instant>,
+	/// Told whenever a command that's waited for exits, so the actions thread wakes up
+	exited: (Sender<()>, Receiver<()>),
+}
+
+impl Default for Launcher {
+	fn default() -> Self {
+		Self { last_run: HashMap::new(), exited: crossbeam_channel::unbounded() }
+	}
+}
+
+impl Launcher {
+	pub fn exited(&self) -> &Receiver<()> {
+		&self.exited.1
+	}
+
+	/// Start the command, it's waited for on a thread of its own. If it's a `wait` one, the returned receiver
+	/// disconnects once it exits.
+	pub fn run(&mut self, exec: &ExecMapping) -> Option<Receiver<()>> {
+		let now = Instant::now();
+		let id = (exec.command.clone(), exec.args.clone());
+		if let Some(last) = self.last_run.get(&id) {
+			if now.duration_since(*last) < Duration::from_millis(exec.debounce) {
+				return None;
+			}
+		}
+		self.last_run.insert(id, now);
+
+		let mut command = Command::new(&exec.command);
+		command.args(&exec.args).envs(&exec.env);
+		if !exec.wait {
+			// Its own process group, so Ctrl+C on tamepad doesn't take it down too
+			command.process_group(0);
+		}
+		let mut child = match command.spawn() {
+			Ok(child) => child,
+			Err(e) => {
+				println!("Exec {}: {:?}", exec.command, e);
+				return None;
+			}
+		};
+		if !exec.wait {
+			// Someone has to reap it
+			std::thread::spawn(move || child.wait());
+			return None;
+		}
+		let (done, waiting) = crossbeam_channel::bounded::<()>(0);
+		let exited = self.exited.0.clone();
+		let name = exec.command.clone();
+		std::thread::spawn(move || {
+			match child.wait() {
+				Ok(status) if !status.success() => println!("Exec {}: {}", name, status),
+				Err(e) => println!("Exec {}: {:?}", name, e),
+				_ => {}
+			}
+			drop(done);
+			let _ = exited.send(());
+		});
+		Some(waiting)
+	}
+}
@@ -0,0 +1,198 @@
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use crossbeam_channel::Sender;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt, EventMask, Window};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+
+use crate::types::{FocusBackend, FocusConfig, FocusedWindow, MainEvent};
+
+/// Tell the state thread whenever another window gets the focus, until the state thread is gone.
+pub fn focus_task(config: FocusConfig, main_sender: Sender<MainEvent>) -> Result<(), anyhow::Error> {
+	match config.backend {
+		FocusBackend::X11 => x11_focus(main_sender),
+		FocusBackend::Command { command, args, interval } => command_focus(main_sender, &command, &args, interval),
+	}
+}
+
+fn command_focus(
+	main_sender: Sender<MainEvent>,
+	command: &str,
+	args: &[String],
+	interval: u64,
+) -> Result<(), anyhow::Error> {
+	let mut last = None;
+	loop {
+		match Command::new(command).args(args).output() {
+			Ok(output) => {
+				let output = String::from_utf8_lossy(&output.stdout);
+				let mut lines = output.lines();
+				let class = lines.next().unwrap_or_default().trim().to_string();
+				let title = lines.next().unwrap_or_default().trim().to_string();
+				let window = FocusedWindow { instance: class.clone(), class, title };
+				if last.as_ref() != Some(&window) {
+					last = Some(window.clone());
+					if main_sender.send(MainEvent::Focus(window)).is_err() {
+						return Ok(());
+					}
+				}
+			}
+			Err(e) => println!("Focus {}: {:?}", command, e),
+		}
+		std::thread::sleep(Duration::from_millis(interval));
+	}
+}
+
+struct Atoms {
+	active_window: Atom,
+	wm_name: Atom,
+	utf8_string: Atom,
+}
+
+fn x11_focus(main_sender: Sender<MainEvent>) -> Result<(), anyhow::Error> {
+	let (conn, screen) = x11rb::connect(None).map_err(|e| anyhow!("Can't connect to the X server: {}", e))?;
+	let root = conn.setup().roots[screen].root;
+	let atoms = Atoms {
+		active_window: conn.intern_atom(false, b"_NET_ACTIVE_WINDOW")?.reply()?.atom,
+		wm_name: conn.intern_atom(false, b"_NET_WM_NAME")?.reply()?.atom,
+		utf8_string: conn.intern_atom(false, b"UTF8_STRING")?.reply()?.atom,
+	};
+	conn.change_window_attributes(root, &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE))?;
+
+	let mut active = None;
+	let mut last = None;
+	loop {
+		let window = active_window(&conn, root, &atoms);
+		if window != active {
+			// Watch the focused window too, so e.g. switching browser tabs changes the title
+			if let Some(window) = window {
+				let events = ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
+				let _ = conn.change_window_attributes(window, &events);
+			}
+			active = window;
+		}
+		let focused = active.map(|window| window_info(&conn, window, &atoms)).unwrap_or_default();
+		if last.as_ref() != Some(&focused) {
+			last = Some(focused.clone());
+			if main_sender.send(MainEvent::Focus(focused)).is_err() {
+				return Ok(());
+			}
+		}
+
+		conn.flush()?;
+		loop {
+			if let Event::PropertyNotify(e) = conn.wait_for_event()? {
+				let title = Some(e.window) == active && (e.atom == atoms.wm_name || e.atom == u32::from(AtomEnum::WM_NAME));
+				if (e.window == root && e.atom == atoms.active_window) || title {
+					break;
+				}
+			}
+		}
+	}
+}
+
+fn active_window(conn: &RustConnection, root: Window, atoms: &Atoms) -> Option<Window> {
+	let reply = conn.get_property(false, root, atoms.active_window, AtomEnum::WINDOW, 0, 1).ok()?.reply().ok()?;
+	let window = reply.value32()?.next();
+	window.filter(|window| *window != x11rb::NONE)
+}
+
+fn window_info(conn: &RustConnection, window: Window, atoms: &Atoms) -> FocusedWindow {
+	// The window may already be gone, then it's just nothing in particular
+	let property = |property: Atom, type_: Atom| -> Option<Vec<u8>> {
+		let reply = conn.get_property(false, window, property, type_, 0, u32::MAX).ok()?.reply().ok()?;
+		Some(reply.value)
+	};
+	let class = property(AtomEnum::WM_CLASS.into(), AtomEnum::STRING.into()).unwrap_or_default();
+	let title = property(atoms.wm_name, atoms.utf8_string)
+		.filter(|title| !title.is_empty())
+		.or_else(|| property(AtomEnum::WM_NAME.into(), AtomEnum::STRING.into()))
+		.unwrap_or_default();
+	parse_window(&class, &title)
+}
+
+/// WM_CLASS is the instance and then the class, each ended by a NUL.
+fn parse_window(class: &[u8], title: &[u8]) -> FocusedWindow {
+	let mut class = class.split(|b| *b == 0).map(|s| String::from_utf8_lossy(s).into_owned());
+	FocusedWindow {
+		instance: class.next().unwrap_or_default(),
+		class: class.next().unwrap_or_default(),
+		title: String::from_utf8_lossy(title).into_owned(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_class_and_title() {
+		let window = parse_window(b"navigator\0firefox\0", "Tabs – Mozilla Firefox".as_bytes());
+		assert_eq!(window.instance, "navigator");
+		assert_eq!(window.class, "firefox");
+		assert_eq!(window.title, "Tabs – Mozilla Firefox");
+	}
+
+	#[test]
+	fn missing_properties_are_empty() {
+		assert_eq!(parse_window(b"", b""), FocusedWindow::default());
+		let window = parse_window(b"xterm", b"");
+		assert_eq!((window.instance.as_str(), window.class.as_str()), ("xterm", ""));
+	}
+
+	/// Against a real X server, e.g. `Xvfb :99 & DISPLAY=:99 cargo test -- --ignored x11`. Without a window manager
+	/// the test sets _NET_ACTIVE_WINDOW itself.
+	#[test]
+	#[ignore = "needs an X server in $DISPLAY"]
+	fn x11_follows_the_active_window_and_its_title() {
+		use x11rb::protocol::xproto::{CreateWindowAux, PropMode, WindowClass};
+		use x11rb::wrapper::ConnectionExt as _;
+
+		let (conn, screen) = x11rb::connect(None).unwrap();
+		let root = conn.setup().roots[screen].root;
+		let atom = |name: &[u8]| conn.intern_atom(false, name).unwrap().reply().unwrap().atom;
+		let active_window = atom(b"_NET_ACTIVE_WINDOW");
+		let (wm_name, utf8_string) = (atom(b"_NET_WM_NAME"), atom(b"UTF8_STRING"));
+		let set_title = |window: Window, title: &str| {
+			conn.change_property8(PropMode::REPLACE, window, wm_name, utf8_string, title.as_bytes()).unwrap();
+			conn.flush().unwrap();
+		};
+		let create = |class: &[u8], title: &str| {
+			let window = conn.generate_id().unwrap();
+			let aux = CreateWindowAux::new();
+			conn.create_window(0, window, root, 0, 0, 10, 10, 0, WindowClass::INPUT_OUTPUT, 0, &aux).unwrap();
+			conn.change_property8(PropMode::REPLACE, window, AtomEnum::WM_CLASS, AtomEnum::STRING, class).unwrap();
+			set_title(window, title);
+			window
+		};
+		let activate = |window: Window| {
+			conn.change_property32(PropMode::REPLACE, root, active_window, AtomEnum::WINDOW, &[window]).unwrap();
+			conn.flush().unwrap();
+		};
+		let editor = create(b"code\0Code\0", "main.rs");
+		let terminal = create(b"xterm\0XTerm\0", "bash");
+		activate(editor);
+
+		let (sender, receiver) = crossbeam_channel::unbounded();
+		std::thread::spawn(move || x11_focus(sender));
+		let next = || match receiver.recv_timeout(Duration::from_secs(2)).expect("no focus event") {
+			MainEvent::Focus(window) => (window.class, window.title),
+			_ => panic!("not a focus event"),
+		};
+		let focused = |class: &str, title: &str| (class.to_string(), title.to_string());
+
+		assert_eq!(next(), focused("Code", "main.rs"));
+		activate(terminal);
+		assert_eq!(next(), focused("XTerm", "bash"));
+		set_title(terminal, "vim");
+		assert_eq!(next(), focused("XTerm", "vim"));
+		// Only the focused window's title counts
+		set_title(editor, "lib.rs");
+		activate(editor);
+		assert_eq!(next(), focused("Code", "lib.rs"));
+		assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+	}
+}
@@ -17,7 +17,7 @@ use winit::event_loop::{EventLoop, EventLoopBuilder};
 use winit::window::{Window, WindowBuilder};
 
 use crate::state::{CachedConfig, State};
-use crate::types::{ButtonHandler, StateMapping};
+use crate::types::ButtonHandler;
 
 #[derive(Debug, Clone)]
 pub enum UIEvent {
@@ -204,11 +204,7 @@ fn render_gui<T: Renderer>(
 		if let Some(s) = &hdl.label {
 			draw_label(canvas, s, x, y, x_off);
 		} else if let Some(m) = &hdl.map {
-			let s = match m {
-				StateMapping::Key(key) => key.into_static_str(),
-				StateMapping::Overlay(name) => name.as_str(),
-			};
-			draw_label(canvas, s, x, y, x_off);
+			draw_label(canvas, &m.label(), x, y, x_off);
 		}
 	};
 
@@ -284,11 +280,7 @@ fn render_gui<T: Renderer>(
 				if let Some(s) = &hdl.label {
 					draw_label(canvas, s, c.draw.x, c.draw.y, c.draw.label_offset);
 				} else if let Some(m) = &hdl.map {
-					let s = match m {
-						StateMapping::Key(key) => key.into_static_str(),
-						StateMapping::Overlay(name) => name.as_str(),
-					};
-					draw_label(canvas, s, c.draw.x, c.draw.y, c.draw.label_offset);
+					draw_label(canvas, &m.label(), c.draw.x, c.draw.y, c.draw.label_offset);
 				}
 			}
 		}
@@ -307,33 +299,29 @@ fn render_gui<T: Renderer>(
 			let hdl = state.find_stick_handler(config, s_idx);
 			if let Some((_, hdl)) = hdl {
 				if let Some(ch) = &hdl.circle {
-					for (sec_idx, sec) in ch.sectors.iter().enumerate() {
-						let next = ch.sectors.get(sec_idx + 1).or(ch.sectors.get(0)).unwrap();
-
-						let mut p = Path::new();
-						p.move_to(
-							x - r * sec.from_degrees.to_radians().cos() as f32,
-							y - r * sec.from_degrees.to_radians().sin() as f32,
-						);
-						p.line_to(x, y);
-						p.line_to(
-							x - r * next.from_degrees.to_radians().cos() as f32,
-							y - r * next.from_degrees.to_radians().sin() as f32,
-						);
-						p.close();
-						p.arc(
-							x,
-							y,
-							r,
-							next.from_degrees.to_radians() as f32 + PI,
-							sec.from_degrees.to_radians() as f32 + PI,
-							Solidity::Solid,
-						);
-						shapes.push(Draw::path(
-							p,
-							if ss.sector.is_some_and(|a| a == sec_idx) { Some(&active_fill) } else { None },
-							Some(&btn_stroke),
-						));
+					for (ring_idx, ring) in ch.rings.iter().enumerate() {
+						// Each ring reaches from its own min_value out to where the next one starts
+						let inner = r * ring.min_value.max(0.) as f32;
+						let outer = ch.rings.get(ring_idx + 1).map_or(r, |next| r * next.min_value as f32);
+						for (sec_idx, sec) in ring.sectors.iter().enumerate() {
+							let next = ring.sectors.get(sec_idx + 1).or(ring.sectors.get(0)).unwrap();
+							let from = sec.from_degrees.to_radians() as f32 + PI;
+							let to = next.from_degrees.to_radians() as f32 + PI;
+
+							let mut p = Path::new();
+							p.arc(x, y, outer, to, from, Solidity::Solid);
+							if inner > 0. {
+								p.arc(x, y, inner, from, to, Solidity::Hole);
+							} else {
+								p.line_to(x, y);
+							}
+							p.close();
+							shapes.push(Draw::path(
+								p,
+								if ss.sector.is_some_and(|a| a == (ring_idx, sec_idx)) { Some(&active_fill) } else { None },
+								Some(&btn_stroke),
+							));
+						}
 					}
 				}
 			}
@@ -348,11 +336,57 @@ fn render_gui<T: Renderer>(
 			p.arc(px, py, c.point.border_radius * scale, 0., TAU, Solidity::Solid);
 			shapes.push(Draw::path(p, Some(&btn_fill), Some(&btn_stroke)));
 
-			if ss.sector.is_some() {
+			for (dir, _) in ss.dpad.iter().enumerate().filter(|(_, held)| **held) {
+				let degrees = (dir as f32 * 90.).to_radians();
+				let mut p = Path::new();
+				p.arc(x - r * degrees.cos(), y - r * degrees.sin(), c.point.fill_radius * scale, 0., TAU, Solidity::Solid);
+				shapes.push(Draw::path(p, Some(&active_fill), None));
+			}
+
+			if ss.sector.is_some() || ss.dpad.contains(&true) {
 				let mut p = Path::new();
 				p.arc(px, py, c.point.fill_radius * scale, 0., TAU, Solidity::Solid);
 				shapes.push(Draw::path(p, Some(&active_fill), None));
 			}
+
+			if let Some(swipe) = state.swipe.as_ref().filter(|s| s.stick == s_idx && !s.candidates.is_empty()) {
+				let words = swipe
+					.candidates
+					.iter()
+					.enumerate()
+					.map(|(i, word)| if i == swipe.selected { format!("[{}]", word) } else { word.clone() })
+					.collect::<Vec<String>>()
+					.join("  ");
+				draw_label(canvas, &words, c.draw.x, c.draw.y + c.draw.border_radius + c.draw.label_offset, 0.);
+			}
+		}
+
+		// Show what can come next under the last button of a pending sequence
+		let continuations = state.sequence_continuations(config);
+		let last = state.sequence.last().and_then(|btn| state.config.config.buttons.iter().find(|c| c.button == *btn));
+		if let (Some(c), false) = (last, continuations.is_empty()) {
+			let pending = state.sequence.iter().map(|btn| btn.into()).collect::<Vec<&'static str>>().join(" ");
+			let lines = [pending].into_iter().chain(continuations.iter().map(|(btn, label)| {
+				let name: &'static str = btn.into();
+				format!("{}: {}", name, label)
+			}));
+			for (i, line) in lines.enumerate() {
+				let y = c.draw.y + c.draw.border_radius + c.draw.label_offset * (i + 1) as f32;
+				draw_label(canvas, &line, c.draw.x, y, 0.);
+			}
+		}
+
+		if let Some(prediction) = &config.config.prediction {
+			if !state.suggestions.is_empty() {
+				let words = state
+					.suggestions
+					.iter()
+					.enumerate()
+					.map(|(i, word)| format!("{} {}", i + 1, word))
+					.collect::<Vec<String>>()
+					.join("  ");
+				draw_label(canvas, &words, prediction.x, prediction.y, 0.);
+			}
 		}
 	}
 	
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+
+use anyhow::{anyhow, bail};
+use xkbcommon_dl::{xkb_context_flags, xkb_keymap_compile_flags, xkb_keymap_format, XKB_MOD_INVALID};
+
+use crate::linux::key_for_code;
+use crate::types::Key;
+
+/// A key and the modifiers that have to be held with it to produce a character.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyStroke {
+	pub key: Key,
+	pub mods: Vec<Key>,
+}
+
+/// Which key strokes produce which characters.
+#[derive(Clone, Debug, Default)]
+pub struct Keymap {
+	chars: HashMap<char, KeyStroke>,
+}
+
+const US_PLAIN: &str = "`1234567890-=qwertyuiop[]\\asdfghjkl;'zxcvbnm,./";
+const US_SHIFTED: &str = "~!@#$%^&*()_+QWERTYUIOP{}|ASDFGHJKL:\"ZXCVBNM<>?";
+const US_KEYS: [Key; 47] = [
+	Key::Grave,
+	Key::Num1,
+	Key::Num2,
+	Key::Num3,
+	Key::Num4,
+	Key::Num5,
+	Key::Num6,
+	Key::Num7,
+	Key::Num8,
+	Key::Num9,
+	Key::Num0,
+	Key::Minus,
+	Key::Equal,
+	Key::Q,
+	Key::W,
+	Key::E,
+	Key::R,
+	Key::T,
+	Key::Y,
+	Key::U,
+	Key::I,
+	Key::O,
+	Key::P,
+	Key::LeftBrace,
+	Key::RightBrace,
+	Key::Backslash,
+	Key::A,
+	Key::S,
+	Key::D,
+	Key::F,
+	Key::G,
+	Key::H,
+	Key::J,
+	Key::K,
+	Key::L,
+	Key::Semicolon,
+	Key::Apostrophe,
+	Key::Z,
+	Key::X,
+	Key::C,
+	Key::V,
+	Key::B,
+	Key::N,
+	Key::M,
+	Key::Comma,
+	Key::Dot,
+	Key::Slash,
+];
+
+impl Keymap {
+	/// The plain US QWERTY layout, used when nothing better is configured.
+	pub fn us() -> Self {
+		let mut keymap = Self::default();
+		for ((plain, shifted), key) in US_PLAIN.chars().zip(US_SHIFTED.chars()).zip(US_KEYS) {
+			keymap.insert(plain, KeyStroke { key, mods: vec![] });
+			keymap.insert(shifted, KeyStroke { key, mods: vec![Key::LeftShift] });
+		}
+		keymap.insert(' ', KeyStroke { key: Key::Space, mods: vec![] });
+		keymap.insert('\n', KeyStroke { key: Key::Enter, mods: vec![] });
+		keymap.insert('\t', KeyStroke { key: Key::Tab, mods: vec![] });
+		keymap
+	}
+
+	/// Read a keymap as produced by e.g. `xkbcli compile-keymap --layout fr`, using the system's libxkbcommon.
+	/// Every key is tried with no modifiers, Shift, AltGr and Shift+AltGr, simplest first.
+	pub fn from_xkb_file(path: &str) -> Result<Self, anyhow::Error> {
+		let xkb = xkbcommon_dl::xkbcommon_option().ok_or(anyhow!("libxkbcommon is not available"))?;
+		let source = CString::new(std::fs::read(path)?)?;
+
+		let mut keymap = Self::default();
+		unsafe {
+			let context = (xkb.xkb_context_new)(xkb_context_flags::XKB_CONTEXT_NO_FLAGS);
+			if context.is_null() {
+				bail!("Can't create an XKB context");
+			}
+			let xkb_keymap = (xkb.xkb_keymap_new_from_string)(
+				context,
+				source.as_ptr(),
+				xkb_keymap_format::XKB_KEYMAP_FORMAT_TEXT_V1,
+				xkb_keymap_compile_flags::XKB_KEYMAP_COMPILE_NO_FLAGS,
+			);
+			(xkb.xkb_context_unref)(context);
+			if xkb_keymap.is_null() {
+				bail!("Can't compile keymap {}", path);
+			}
+			let xkb_state = (xkb.xkb_state_new)(xkb_keymap);
+
+			let shift = (xkb.xkb_keymap_mod_get_index)(xkb_keymap, c"Shift".as_ptr());
+			// AltGr is ISO_Level3_Shift, which sets Mod5 in practically every layout
+			let level3 = (xkb.xkb_keymap_mod_get_index)(xkb_keymap, c"Mod5".as_ptr());
+			let mut levels = vec![(0, vec![])];
+			if shift != XKB_MOD_INVALID {
+				levels.push((1 << shift, vec![Key::LeftShift]));
+			}
+			if level3 != XKB_MOD_INVALID {
+				levels.push((1 << level3, vec![Key::RightAlt]));
+			}
+			if shift != XKB_MOD_INVALID && level3 != XKB_MOD_INVALID {
+				levels.push(((1 << shift) | (1 << level3), vec![Key::LeftShift, Key::RightAlt]));
+			}
+
+			let min = (xkb.xkb_keymap_min_keycode)(xkb_keymap);
+			let max = (xkb.xkb_keymap_max_keycode)(xkb_keymap);
+			for (mask, mods) in levels {
+				(xkb.xkb_state_update_mask)(xkb_state, mask, 0, 0, 0, 0, 0);
+				// XKB keycodes are evdev ones plus 8, and X11 clients can't get anything above 255
+				for code in min.max(8)..=max.min(255) {
+					let Some(key) = key_for_code(code - 8) else { continue };
+					let c = (xkb.xkb_state_key_get_utf32)(xkb_state, code);
+					if let Some(c) = char::from_u32(c).filter(|c| !c.is_control()) {
+						keymap.insert(c, KeyStroke { key, mods: mods.clone() });
+					}
+				}
+			}
+
+			(xkb.xkb_state_unref)(xkb_state);
+			(xkb.xkb_keymap_unref)(xkb_keymap);
+		}
+
+		// Control characters come out as e.g. \r for Return, so add the ones worth typing by hand
+		keymap.insert('\n', KeyStroke { key: Key::Enter, mods: vec![] });
+		keymap.insert('\t', KeyStroke { key: Key::Tab, mods: vec![] });
+		Ok(keymap)
+	}
+
+	/// Remember a way to type `c`, unless there already is one.
+	pub fn insert(&mut self, c: char, stroke: KeyStroke) {
+		self.chars.entry(c).or_insert(stroke);
+	}
+
+	pub fn lookup(&self, c: char) -> Option<&KeyStroke> {
+		self.chars.get(&c)
+	}
+
+	/// The character `key` produces with these modifiers held, either shift counts as left shift.
+	pub fn char_for(&self, key: Key, mods: &[Key]) -> Option<char> {
+		let mut mods: Vec<Key> =
+			mods.iter().map(|m| if *m == Key::RightShift { Key::LeftShift } else { *m }).collect();
+		mods.sort_by_key(|m| *m as usize);
+		mods.dedup();
+		self.chars.iter().find_map(|(c, stroke)| {
+			let mut stroke_mods = stroke.mods.clone();
+			stroke_mods.sort_by_key(|m| *m as usize);
+			(stroke.key == key && stroke_mods == mods).then_some(*c)
+		})
+	}
+}
@@ -1,14 +1,23 @@
-use std::{fs::OpenOptions, io};
+use std::{fs::File, fs::OpenOptions, io};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 use std::os::unix::fs::OpenOptionsExt;
 
-use crossbeam_channel::select;
+use crossbeam_channel::{select, TryRecvError};
 use input_linux::{
-	EventKind, EventTime, InputEvent, InputId, KeyEvent, KeyState, SynchronizeEvent, SynchronizeKind, UInputHandle,
+	AbsoluteAxis, AbsoluteEvent, AbsoluteInfo, AbsoluteInfoSetup, EventKind, EventTime, InputEvent, InputId,
+	InputProperty, KeyEvent, KeyState, SynchronizeEvent, SynchronizeKind, UInputHandle,
 };
+use input_linux::sys::input_event;
 use lazy_static::lazy_static;
 use strum::IntoEnumIterator;
 
-use crate::types::{Action, Key};
+use crate::events::{publish, StateEvent};
+use crate::exec::Launcher;
+use crate::keymap::{Keymap, KeyStroke};
+use crate::types::{
+	Action, BusType, DeviceConfig, Key, MacroStep, MainEvent, OutputConfig, PadAxis, TextConfig, UnicodeInput,
+};
 
 lazy_static! {
 	// This is very stupid but at least I only do it once.
@@ -18,66 +27,629 @@ lazy_static! {
 	}).collect();
 }
 
+/// The key with this evdev code, if there is one.
+pub fn key_for_code(code: u32) -> Option<Key> {
+	Key::iter().find(|key| KEY_TO_UINPUT[*key as usize] as u32 == code && *key != Key::Reserved)
+}
+
+const ZERO: EventTime = EventTime::new(0, 0);
+
+/// What a device looks like when the config doesn't say otherwise.
+struct DeviceDefaults {
+	name: &'static str,
+	product: u16,
+	keys: Vec<Key>,
+}
+
+const GAMEPAD_KEYS: [Key; 17] = [
+	Key::ButtonSouth,
+	Key::ButtonEast,
+	Key::ButtonNorth,
+	Key::ButtonWest,
+	Key::ButtonTL,
+	Key::ButtonTR,
+	Key::ButtonTL2,
+	Key::ButtonTR2,
+	Key::ButtonSelect,
+	Key::ButtonStart,
+	Key::ButtonMode,
+	Key::ButtonThumbl,
+	Key::ButtonThumbr,
+	Key::ButtonDpadUp,
+	Key::ButtonDpadDown,
+	Key::ButtonDpadLeft,
+	Key::ButtonDpadRight,
+];
+
+const POINTER_KEYS: [Key; 4] = [Key::ButtonToolPen, Key::ButtonTouch, Key::ButtonStylus, Key::ButtonStylus2];
+
+fn pointer_axis_setup(axis: AbsoluteAxis) -> AbsoluteInfoSetup {
+	AbsoluteInfoSetup {
+		axis,
+		info: AbsoluteInfo {
+			minimum: 0,
+			maximum: 65535,
+			// Made up, but tablets without one get complained about
+			resolution: 100,
+			..Default::default()
+		},
+	}
+}
+
+fn pad_axis_setup(axis: PadAxis) -> AbsoluteInfoSetup {
+	let (axis, minimum, maximum, fuzz, flat) = match axis {
+		PadAxis::X => (AbsoluteAxis::X, -32768, 32767, 16, 128),
+		PadAxis::Y => (AbsoluteAxis::Y, -32768, 32767, 16, 128),
+		PadAxis::Rx => (AbsoluteAxis::RX, -32768, 32767, 16, 128),
+		PadAxis::Ry => (AbsoluteAxis::RY, -32768, 32767, 16, 128),
+		PadAxis::Z => (AbsoluteAxis::Z, 0, 255, 0, 0),
+		PadAxis::Rz => (AbsoluteAxis::RZ, 0, 255, 0, 0),
+		PadAxis::Hat0X => (AbsoluteAxis::Hat0X, -1, 1, 0, 0),
+		PadAxis::Hat0Y => (AbsoluteAxis::Hat0Y, -1, 1, 0, 0),
+	};
+	AbsoluteInfoSetup {
+		axis,
+		info: AbsoluteInfo {
+			minimum,
+			maximum,
+			fuzz,
+			flat,
+			..Default::default()
+		},
+	}
+}
+
+/// Owns a uinput device and remembers which keys it is holding down, so they can always be released,
+/// including when the thread unwinds.
+struct VirtualDevice {
+	uhandle: UInputHandle<File>,
+	supported: Vec<bool>,
+	/// Keys being held and by how many mappings, so a modifier shared by two chords stays down until both let go
+	held: Vec<(Key, usize)>,
+	axes: Vec<AbsoluteInfoSetup>,
+}
+
+fn bus_type(bus: BusType) -> u16 {
+	use input_linux::sys;
+	match bus {
+		BusType::Usb => sys::BUS_USB,
+		BusType::Bluetooth => sys::BUS_BLUETOOTH,
+		BusType::Virtual => sys::BUS_VIRTUAL,
+		BusType::Host => sys::BUS_HOST,
+		BusType::I8042 => sys::BUS_I8042,
+	}
+}
+
+impl VirtualDevice {
+	fn new(
+		config: &DeviceConfig,
+		defaults: DeviceDefaults,
+		axes: Vec<AbsoluteInfoSetup>,
+		props: &[InputProperty],
+	) -> io::Result<Self> {
+		let uinput_file = OpenOptions::new()
+			.read(false)
+			.write(true)
+			.custom_flags(libc::O_NONBLOCK)
+			.open("/dev/uinput")?;
+		let uhandle = UInputHandle::new(uinput_file);
+
+		let mut supported = vec![false; Key::iter().len()];
+		config
+			.keys
+			.as_ref()
+			.unwrap_or(&defaults.keys)
+			.iter()
+			.for_each(|key| supported[*key as usize] = true);
+
+		uhandle.set_evbit(EventKind::Key)?;
+		for key in Key::iter().filter(|key| supported[*key as usize]) {
+			uhandle.set_keybit(KEY_TO_UINPUT[key as usize])?;
+		}
+
+		if !axes.is_empty() {
+			uhandle.set_evbit(EventKind::Absolute)?;
+			for abs in &axes {
+				uhandle.set_absbit(abs.axis)?;
+			}
+		}
+
+		for prop in props {
+			uhandle.set_propbit(*prop)?;
+		}
+
+		// uhandle.set_evbit(EventKind::Relative)?;
+		// uhandle.set_relbit(RelativeAxis::X)?;
+		// uhandle.set_relbit(RelativeAxis::Y)?;
+
+		let input_id = InputId {
+			bustype: bus_type(config.bus.unwrap_or(BusType::Usb)),
+			vendor: config.vendor.unwrap_or(0x1234),
+			product: config.product.unwrap_or(defaults.product),
+			version: config.version.unwrap_or(0),
+		};
+		let device_name = config.name.as_deref().unwrap_or(defaults.name);
+		uhandle.create(&input_id, device_name.as_bytes(), 0, &axes)?;
+
+		Ok(Self {
+			uhandle,
+			supported,
+			held: Vec::new(),
+			axes,
+		})
+	}
+
+	fn supports(&self, key: Key) -> bool {
+		self.supported[key as usize]
+	}
+
+	fn is_held(&self, key: Key) -> bool {
+		self.held.iter().any(|(k, _)| *k == key)
+	}
+
+	/// Press or release a key, only sending the event when the first press comes or the last release goes.
+	fn key(&mut self, key: Key, down: bool) {
+		if !self.supports(key) {
+			println!("Key not enabled for the device: {:?}", key);
+			return;
+		}
+		let pos = self.held.iter().position(|(k, _)| *k == key);
+		match (down, pos) {
+			(true, Some(pos)) => self.held[pos].1 += 1,
+			(true, None) => {
+				self.held.push((key, 1));
+				self.emit(key, true);
+			}
+			(false, Some(pos)) => {
+				self.held[pos].1 -= 1;
+				if self.held[pos].1 == 0 {
+					self.held.remove(pos);
+					self.emit(key, false);
+				}
+			}
+			(false, None) => {}
+		}
+	}
+
+	/// Send a key event without counting it as held, for taps and for briefly letting go of modifiers.
+	fn emit(&self, key: Key, down: bool) {
+		publish(StateEvent::Key { key, down });
+		self.write(&[*InputEvent::from(KeyEvent::new(
+			ZERO,
+			KEY_TO_UINPUT[key as usize],
+			if down { KeyState::PRESSED } else { KeyState::RELEASED },
+		))
+		.as_raw()]);
+	}
+
+	/// Move some axes, values being -1.0 to 1.0 of their range (or 0.0 to 1.0 if there is no negative side).
+	fn move_axes(&mut self, values: &[(AbsoluteAxis, f64)]) {
+		let mut events = Vec::new();
+		for (axis, value) in values {
+			let Some(setup) = self.axes.iter().find(|a| a.axis == *axis) else {
+				println!("Axis not enabled for the device: {:?}", axis);
+				continue;
+			};
+			let value = (value * setup.info.maximum as f64).round() as i32;
+			let value = value.clamp(setup.info.minimum, setup.info.maximum);
+			events.push(*InputEvent::from(AbsoluteEvent::new(ZERO, *axis, value)).as_raw());
+		}
+		if !events.is_empty() {
+			self.write(&events);
+		}
+	}
+
+	/// Press the held modifiers again after typing text let go of them.
+	fn restore_modifiers(&self) {
+		for (key, _) in &self.held {
+			if MODIFIERS.contains(key) {
+				self.emit(*key, true);
+			}
+		}
+	}
+
+	fn release_all(&mut self) {
+		for (key, _) in std::mem::take(&mut self.held).into_iter().rev() {
+			self.emit(key, false);
+		}
+	}
+
+	/// Put the gamepad's sticks and triggers back to rest, the pointer stays where it is.
+	fn center_axes(&mut self) {
+		let centered: Vec<_> =
+			self.axes.iter().map(|a| *InputEvent::from(AbsoluteEvent::new(ZERO, a.axis, 0)).as_raw()).collect();
+		if !centered.is_empty() {
+			self.write(&centered);
+		}
+	}
+
+	fn write(&self, events: &[input_event]) {
+		let mut events = events.to_vec();
+		events.push(*InputEvent::from(SynchronizeEvent::new(ZERO, SynchronizeKind::Report, 0)).as_raw());
+		match self.uhandle.write(events.as_slice()) {
+			Ok(_) => {}
+			Err(e) => {
+				println!("{:?}", e);
+			}
+		}
+	}
+}
+
+impl Drop for VirtualDevice {
+	fn drop(&mut self) {
+		self.release_all();
+		if let Err(e) = self.uhandle.dev_destroy() {
+			println!("{:?}", e);
+		}
+	}
+}
+
+const MODIFIERS: [Key; 8] = [
+	Key::LeftShift,
+	Key::RightShift,
+	Key::LeftCtrl,
+	Key::RightCtrl,
+	Key::LeftAlt,
+	Key::RightAlt,
+	Key::LeftMeta,
+	Key::RightMeta,
+];
+
+fn tap_stroke(events: &mut Vec<(Key, bool)>, stroke: &KeyStroke) {
+	for key in &stroke.mods {
+		events.push((*key, true));
+	}
+	events.push((stroke.key, true));
+	events.push((stroke.key, false));
+	for key in stroke.mods.iter().rev() {
+		events.push((*key, false));
+	}
+}
+
+fn type_unicode(events: &mut Vec<(Key, bool)>, keymap: &Keymap, start: &[Key], end: Key, c: char) {
+	tap_stroke(events, &KeyStroke { key: start[start.len() - 1], mods: start[..start.len() - 1].to_vec() });
+	for digit in format!("{:x}", c as u32).chars() {
+		if let Some(stroke) = keymap.lookup(digit) {
+			tap_stroke(events, stroke);
+		}
+	}
+	tap_stroke(events, &KeyStroke { key: end, mods: vec![] });
+}
+
+/// The key events that type `text` on the keyboard as it is now, the held modifiers are left let go of.
+fn text_events(keyboard: &VirtualDevice, keymap: &Keymap, config: &TextConfig, text: &str) -> Vec<(Key, bool)> {
+	let mut events = Vec::new();
+	// Whatever modifiers are held would change what gets typed, so let go of them for a moment
+	let held_mods: Vec<Key> = keyboard.held.iter().map(|(key, _)| *key).filter(|key| MODIFIERS.contains(key)).collect();
+	for key in &held_mods {
+		events.push((*key, false));
+	}
+	for c in text.chars() {
+		if let Some(stroke) = keymap.lookup(c) {
+			tap_stroke(&mut events, stroke);
+			continue;
+		}
+		match &config.unicode {
+			UnicodeInput::None => println!("Can't type {:?}", c),
+			UnicodeInput::CtrlShiftU => {
+				type_unicode(&mut events, keymap, &[Key::LeftCtrl, Key::LeftShift, Key::U], Key::Space, c)
+			}
+			UnicodeInput::Custom { start, end } if !start.is_empty() => {
+				type_unicode(&mut events, keymap, start, *end, c)
+			}
+			UnicodeInput::Custom { .. } => println!("Unicode input has no start keys, can't type {:?}", c),
+		}
+	}
+	events
+}
+
+/// All the virtual devices, keys the gamepad or the pointer have go to them, everything else to the keyboard.
+struct Devices {
+	keyboard: VirtualDevice,
+	gamepad: Option<VirtualDevice>,
+	pointer: Option<VirtualDevice>,
+}
+
+impl Devices {
+	fn key(&mut self, key: Key, down: bool) {
+		match [&mut self.gamepad, &mut self.pointer].into_iter().flatten().find(|dev| dev.supports(key)) {
+			Some(dev) => dev.key(key, down),
+			None => self.keyboard.key(key, down),
+		}
+	}
+
+	/// What pressing `key` on the keyboard types, as far as word prediction cares.
+	fn typed_event(&self, keymap: &Keymap, key: Key) -> Option<MainEvent> {
+		if MODIFIERS.contains(&key) || [&self.gamepad, &self.pointer].into_iter().flatten().any(|dev| dev.supports(key)) {
+			return None;
+		}
+		if key == Key::Backspace {
+			return Some(MainEvent::Backspace);
+		}
+		let mods: Vec<Key> = MODIFIERS.iter().copied().filter(|m| self.keyboard.is_held(*m)).collect();
+		match keymap.char_for(key, &mods) {
+			Some(c) => Some(MainEvent::Typed(c.to_string())),
+			None => Some(MainEvent::WordBreak),
+		}
+	}
+
+	fn release_all(&mut self) {
+		self.keyboard.release_all();
+		if let Some(gamepad) = &mut self.gamepad {
+			gamepad.release_all();
+			gamepad.center_axes();
+		}
+		if let Some(pointer) = &mut self.pointer {
+			pointer.release_all();
+		}
+	}
+}
+
+impl Drop for Devices {
+	fn drop(&mut self) {
+		// Take the pointer and gamepad away before the keyboard, in reverse of creating them
+		self.pointer.take();
+		self.gamepad.take();
+	}
+}
+
+struct RunningMacro {
+	/// None for text typed with a key delay, which isn't bound to anything that could cancel it
+	id: Option<u64>,
+	steps: VecDeque<MacroStep>,
+	resume_at: Instant,
+	/// Keys pressed by the macro and not released yet
+	held: Vec<Key>,
+	/// Keys of typed text pressed and not released yet, which the devices don't count as held
+	emitted: Vec<Key>,
+	/// A command the macro is waiting for, disconnected when it's done
+	waiting: Option<crossbeam_channel::Receiver<()>>,
+}
+
+impl RunningMacro {
+	/// Let go of whatever the macro still holds, and of text it's halfway through typing.
+	fn release(&mut self, devices: &mut Devices) {
+		for key in self.emitted.drain(..).rev() {
+			if !devices.keyboard.is_held(key) {
+				devices.keyboard.emit(key, false);
+			}
+		}
+		for key in self.held.drain(..).rev() {
+			devices.key(key, false);
+		}
+		if self.steps.iter().any(|step| *step == MacroStep::RestoreModifiers) {
+			devices.keyboard.restore_modifiers();
+		}
+	}
+
+	/// Play steps until a delay or the end, returns whether there's anything left.
+	fn play(
+		&mut self,
+		devices: &mut Devices,
+		keymap: &Keymap,
+		config: &TextConfig,
+		launcher: &mut Launcher,
+		main_sender: &crossbeam_channel::Sender<MainEvent>,
+	) -> bool {
+		while let Some(step) = self.steps.pop_front() {
+			match step {
+				MacroStep::KeyDown(key) => {
+					if let Some(ev) = devices.typed_event(keymap, key) {
+						let _ = main_sender.send(ev);
+					}
+					devices.key(key, true);
+					self.held.push(key);
+				}
+				MacroStep::KeyUp(key) => {
+					if let Some(pos) = self.held.iter().rposition(|other| *other == key) {
+						self.held.remove(pos);
+						devices.key(key, false);
+					}
+				}
+				MacroStep::Tap(key) => {
+					if let Some(ev) = devices.typed_event(keymap, key) {
+						let _ = main_sender.send(ev);
+					}
+					devices.key(key, true);
+					devices.key(key, false);
+				}
+				MacroStep::Text(text) => {
+					let events = text_events(&devices.keyboard, keymap, config, &text);
+					if config.key_delay > 0 {
+						// Played as steps of their own, with a delay after each
+						self.steps.push_front(MacroStep::RestoreModifiers);
+						for (key, down) in events.into_iter().rev() {
+							self.steps.push_front(MacroStep::Delay(config.key_delay));
+							self.steps.push_front(MacroStep::Emit(key, down));
+						}
+					} else {
+						for (key, down) in events {
+							devices.keyboard.emit(key, down);
+						}
+						devices.keyboard.restore_modifiers();
+					}
+					let _ = main_sender.send(MainEvent::Typed(text));
+				}
+				MacroStep::Emit(key, down) => {
+					if down {
+						self.emitted.push(key);
+					} else if let Some(pos) = self.emitted.iter().rposition(|other| *other == key) {
+						self.emitted.remove(pos);
+					}
+					devices.keyboard.emit(key, down);
+				}
+				MacroStep::RestoreModifiers => devices.keyboard.restore_modifiers(),
+				MacroStep::Delay(ms) => {
+					self.resume_at = Instant::now() + Duration::from_millis(ms);
+					return true;
+				}
+				MacroStep::AddOverlay(name) => {
+					let _ = main_sender.send(MainEvent::AddOverlay(name));
+				}
+				MacroStep::RemoveOverlay(name) => {
+					let _ = main_sender.send(MainEvent::RemoveOverlay(name));
+				}
+				MacroStep::Exec(exec) => {
+					if let Some(done) = launcher.run(&exec) {
+						self.waiting = Some(done);
+						return true;
+					}
+				}
+			}
+		}
+		self.release(devices);
+		false
+	}
+}
+
+/// Queue steps after the text still being typed, if any.
+fn type_steps(macros: &mut Vec<RunningMacro>, steps: Vec<MacroStep>) {
+	match macros.iter_mut().find(|m| m.id.is_none()) {
+		Some(typing) => typing.steps.extend(steps),
+		None => macros.push(RunningMacro {
+			id: None,
+			steps: steps.into(),
+			resume_at: Instant::now(),
+			held: Vec::new(),
+			emitted: Vec::new(),
+			waiting: None,
+		}),
+	}
+}
+
 pub fn linux_actions_task(
 	actions: crossbeam_channel::Receiver<Action>,
+	main_sender: crossbeam_channel::Sender<MainEvent>,
+	config: OutputConfig,
 ) -> io::Result<()> {
-	let uinput_file = OpenOptions::new()
-		.read(false)
-		.write(true)
-		.custom_flags(libc::O_NONBLOCK)
-		.open("/dev/uinput")?;
-	let uhandle = UInputHandle::new(uinput_file);
-
-	uhandle.set_evbit(EventKind::Key)?;
-	for key in Key::iter() {
-		uhandle.set_keybit(KEY_TO_UINPUT[key as usize])?;
-	}
-
-	// uhandle.set_evbit(EventKind::Relative)?;
-	// uhandle.set_relbit(RelativeAxis::X)?;
-	// uhandle.set_relbit(RelativeAxis::Y)?;
-
-	let input_id = InputId {
-		bustype: input_linux::sys::BUS_USB,
-		vendor: 0x1234,
-		product: 0x5678,
-		version: 0,
+	let keyboard = VirtualDevice::new(
+		&config.keyboard,
+		DeviceDefaults {
+			name: "Tamepad",
+			product: 0x5678,
+			keys: Key::iter().collect(),
+		},
+		Vec::new(),
+		&[],
+	)?;
+	let gamepad = match &config.gamepad {
+		Some(pad_config) => Some(VirtualDevice::new(
+			pad_config,
+			DeviceDefaults {
+				name: "Tamepad Gamepad",
+				product: 0x5679,
+				keys: GAMEPAD_KEYS.to_vec(),
+			},
+			PadAxis::iter().map(pad_axis_setup).collect(),
+			&[],
+		)?),
+		None => None,
+	};
+	let pointer = match &config.pointer {
+		Some(pointer_config) => Some(VirtualDevice::new(
+			pointer_config,
+			DeviceDefaults {
+				name: "Tamepad Pointer",
+				product: 0x567a,
+				keys: POINTER_KEYS.to_vec(),
+			},
+			vec![pointer_axis_setup(AbsoluteAxis::X), pointer_axis_setup(AbsoluteAxis::Y)],
+			&[InputProperty::Direct],
+		)?),
+		None => None,
+	};
+	// Dropping the devices releases whatever is still held before destroying them.
+	let mut devices = Devices { keyboard, gamepad, pointer };
+
+	let keymap = match &config.text.keymap {
+		Some(path) => Keymap::from_xkb_file(path).unwrap_or_else(|e| {
+			println!("Keymap {}: {:?}, falling back to US", path, e);
+			Keymap::us()
+		}),
+		None => Keymap::us(),
 	};
-	let device_name = b"Tamepad";
-	uhandle.create(&input_id, device_name, 0, &[])?;
 
-	const ZERO: EventTime = EventTime::new(0, 0);
+	let mut macros: Vec<RunningMacro> = Vec::new();
+	let mut launcher = Launcher::default();
+	let exited = launcher.exited().clone();
+
 	loop {
-		let _ = select! {
+		let timeout = match macros.iter().filter(|m| m.waiting.is_none()).map(|m| m.resume_at).min() {
+			Some(at) => at.saturating_duration_since(Instant::now()),
+			None => Duration::from_secs(1),
+		};
+
+		select! {
 			recv(actions) -> act => match act {
 				Ok(act) => {
 					// println!("{}", json5::to_string(&act).unwrap());
-					let mut events = Vec::new();
 					match act {
 						Action::KeyDown(key) => {
-							events.push(*InputEvent::from(KeyEvent::new(
-								ZERO,
-								KEY_TO_UINPUT[key as usize],
-								KeyState::PRESSED
-							)).as_raw());
+							if let Some(ev) = devices.typed_event(&keymap, key) {
+								let _ = main_sender.send(ev);
+							}
+							devices.key(key, true);
 						}
-						Action::KeyUp(key) => {
-							events.push(*InputEvent::from(KeyEvent::new(
-								ZERO,
-								KEY_TO_UINPUT[key as usize],
-								KeyState::RELEASED
-							)).as_raw());
+						Action::KeyUp(key) => devices.key(key, false),
+						// Typed by the macro player, after whatever text is still being typed
+						Action::Text(text) => type_steps(&mut macros, vec![MacroStep::Text(text)]),
+						Action::Type(steps) => type_steps(&mut macros, steps),
+						Action::CharDown(c) => match keymap.lookup(c) {
+							Some(stroke) => {
+								for key in stroke.mods.iter().chain([&stroke.key]) {
+									devices.keyboard.key(*key, true);
+								}
+								let _ = main_sender.send(MainEvent::Typed(c.to_string()));
+							}
+							None => println!("No key for {:?} in the keymap", c),
+						},
+						Action::CharUp(c) => {
+							if let Some(stroke) = keymap.lookup(c) {
+								for key in [&stroke.key].into_iter().chain(stroke.mods.iter().rev()) {
+									devices.keyboard.key(*key, false);
+								}
+							}
 						}
-						_ => {}
-					}
-					if !events.is_empty() {
-						events.push(*InputEvent::from(SynchronizeEvent::new(ZERO, SynchronizeKind::Report, 0)).as_raw());
-						match uhandle.write(events.as_slice()) {
-							Ok(_) => {},
-							Err(e) => {
-								println!("{:?}", e);
+						Action::PadAxis(axis, value) => {
+							if let Some(pad) = &mut devices.gamepad {
+								pad.move_axes(&[(pad_axis_setup(axis).axis, value)]);
+							}
+						}
+						Action::PointerMove(x, y) => {
+							if let Some(pointer) = &mut devices.pointer {
+								pointer.move_axes(&[(AbsoluteAxis::X, x), (AbsoluteAxis::Y, y)]);
+								// Bring the pen into proximity so the cursor follows it
+								if !pointer.is_held(Key::ButtonToolPen) {
+									pointer.key(Key::ButtonToolPen, true);
+								}
+							}
+						}
+						// Nothing after it depends on it, so it's never waited for here
+						Action::Exec(exec) => {
+							launcher.run(&exec);
+						}
+						Action::Macro(id, steps) => macros.push(RunningMacro {
+							id: Some(id),
+							steps: steps.into(),
+							resume_at: Instant::now(),
+							held: Vec::new(),
+							emitted: Vec::new(),
+							waiting: None,
+						}),
+						Action::CancelMacro(id) => {
+							if let Some(pos) = macros.iter().position(|m| m.id == Some(id)) {
+								macros.remove(pos).release(&mut devices);
+							}
+						}
+						Action::ReleaseAll => {
+							// Nothing stays held, so no modifiers are given back either
+							for mut m in macros.drain(..) {
+								m.steps.clear();
+								m.release(&mut devices);
 							}
+							devices.release_all();
 						}
+						_ => {}
 					}
 				}
 				Err(_) => {
@@ -85,10 +657,19 @@ pub fn linux_actions_task(
 					break;
 				}
 			},
-		};
-	}
+			recv(exited) -> _ => {}
+			default(timeout) => {}
+		}
 
-	uhandle.dev_destroy()?;
+		let now = Instant::now();
+		macros.retain_mut(|m| {
+			if m.waiting.as_ref().is_some_and(|done| done.try_recv() == Err(TryRecvError::Empty)) {
+				return true;
+			}
+			m.waiting = None;
+			m.resume_at > now || m.play(&mut devices, &keymap, &config.text, &mut launcher, &main_sender)
+		});
+	}
 
 	Ok(())
 }
@@ -5,21 +5,39 @@ use std::sync::{Arc, Mutex};
 use sdl2::event::EventSender;
 use signal_hook::iterator::Signals;
 
+use crate::control::control_task;
+use crate::focus::focus_task;
 use crate::gui::{gui_loop, init_gui, UIEvent};
 use crate::linux::linux_actions_task;
 use crate::sdl::sdl_task;
-use crate::state::state_task;
-use crate::types::{MainEvent, write_schema};
+use crate::state::{CachedConfig, StateExit, state_task};
+use crate::types::{MainEvent, read_config, write_schema};
 
+mod control;
+mod dictionary;
+mod events;
+mod exec;
+mod focus;
 mod gui;
+mod keymap;
 mod linux;
 mod sdl;
 mod state;
 mod types;
 
+const CONFIG_PATH: &str = "configs/default.json5";
+
 fn main() {
 	println!("Hello, world!");
 
+	let config = read_config(CONFIG_PATH).and_then(CachedConfig::new).unwrap_or_else(|e| {
+		println!("Config: {:?}", e);
+		std::process::exit(1);
+	});
+	let output_config = config.config.output.clone();
+	let focus_config = config.config.focus.clone();
+	let control_socket = config.config.control_socket.clone();
+
 	let (event_loop, current_context, gl_display, window, surface) = init_gui();
 
 	let (main_sender, main_receiver) = crossbeam_channel::unbounded();
@@ -38,17 +56,46 @@ fn main() {
 	let ui = ui_loop_proxy.clone();
 	let mr = main_receiver.clone();
 	let state = std::thread::spawn(move || {
-		state_task(input_receiver, actions_sender, ui, mr).unwrap_or_else(|e| {
-			println!("State thread: {:?}", e);
-		})
+		let (mut config, mut focused) = (config, None);
+		loop {
+			let (events, actions) = (input_receiver.clone(), actions_sender.clone());
+			match state_task(events, actions, ui.clone(), mr.clone(), config, CONFIG_PATH, focused) {
+				Ok(StateExit::Reload(new_config, window)) => (config, focused) = (*new_config, window),
+				Ok(StateExit::Quit) => break,
+				Err(e) => {
+					println!("State thread: {:?}", e);
+					break;
+				}
+			}
+		}
 	});
 	
+	let ms = main_sender.clone();
 	let actions = std::thread::spawn(move || {
-		linux_actions_task(actions_receiver).unwrap_or_else(|e| {
+		linux_actions_task(actions_receiver, ms, output_config).unwrap_or_else(|e| {
 			println!("Actions thread: {:?}", e);
 		})
 	});
 	
+	// These aren't joined, they're waiting on the X server, a command or the socket and go away with the process
+	if let Some(focus_config) = focus_config {
+		let ms = main_sender.clone();
+		std::thread::spawn(move || {
+			focus_task(focus_config, ms).unwrap_or_else(|e| {
+				println!("Focus thread: {:?}", e);
+			})
+		});
+	}
+
+	if let Some(path) = control_socket {
+		let ms = main_sender.clone();
+		std::thread::spawn(move || {
+			control_task(path, ms).unwrap_or_else(|e| {
+				println!("Control thread: {:?}", e);
+			})
+		});
+	}
+
 	let ui = ui_loop_proxy.clone();
 	std::thread::spawn(move || {
 		let mut signals = Signals::new(&[
@@ -62,18 +109,27 @@ fn main() {
 
 	write_schema();
 	
-	gui_loop(event_loop, current_context, gl_display, window, surface).unwrap();
+	// Even if the GUI panics, let the other threads shut down first so no keys are left held.
+	let gui_result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+		gui_loop(event_loop, current_context, gl_display, window, surface).unwrap();
+	}));
 	
-	main_sender.send(MainEvent::Quit).unwrap();
+	// The state thread may have panicked and be gone already
+	let _ = main_sender.send(MainEvent::Quit);
 	{
 		let lock = sdl_sender.lock();
 		lock.unwrap().as_mut().unwrap().push_event(sdl2::event::Event::Quit { timestamp: 0 }).unwrap();
 	}
 
 	sdl.join().unwrap();
-	state.join().unwrap();
+	// The actions thread ends once the state thread's sender is gone, after releasing what a panic left held
+	let state_result = state.join();
 	actions.join().unwrap();
 	
+	if let Err(e) = gui_result.and(state_result) {
+		std::panic::resume_unwind(e);
+	}
+	
 	std::process::exit(0);
 	
 }
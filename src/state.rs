@@ -1,18 +1,24 @@
-use std::fs::read_to_string;
 use std::ops::Index;
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 
-use anyhow::anyhow;
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail};
 use crossbeam_channel::select;
 use indexmap::IndexMap;
+use serde_json::json;
 use strum::IntoEnumIterator;
 use winit::event_loop::EventLoopProxy;
 
+use crate::dictionary::{ChordDictionary, Dictionary, Predictor};
+use crate::events::{publish, StateEvent};
 use crate::gui::UIEvent;
 use crate::types::{
-	Action, Axis, Binding, Button, ButtonCombo, ButtonHandler, GamepadConfig, InputEvent, MainEvent, Overlay,
-	StateMapping, StickHandler,
+	Action, Axis, AxisHandler, Binding, Button, ButtonCombo, ButtonHandler, CircleRing, CircleSector, ComboRelease,
+	ControlRequest, DpadHandler, FocusedWindow, GamepadConfig, Gesture, InputEvent, Key, MacroStep, MainEvent,
+	Overlay, PadAxisMapping, RepeatConfig, RepeatMode, StateMapping, StenoConfig, StickDirection, StickHandler,
+	SwipeConfig, read_config,
 };
 
 #[derive(Debug)]
@@ -21,6 +27,14 @@ struct CachedOverlay {
 	buttons: IndexMap<Button, ButtonHandler>,
 	combos: IndexMap<usize, ButtonHandler>,
 	sticks: IndexMap<usize, StickHandler>,
+	axes: IndexMap<Axis, AxisHandler>,
+	sequences: Vec<CachedSequence>,
+}
+#[derive(Debug)]
+struct CachedSequence {
+	buttons: Vec<Button>,
+	timeout: u64,
+	handler: ButtonHandler,
 }
 #[derive(Default, Debug)]
 struct ButtonComboList {
@@ -34,6 +48,7 @@ pub struct ButtonState {
 	handled: bool,
 	pub in_combo: Option<usize>,
 	handle_at: Option<Instant>,
+	pressed_at: Option<Instant>,
 }
 
 impl Default for ButtonState {
@@ -43,126 +58,484 @@ impl Default for ButtonState {
 			handled: true,
 			in_combo: None,
 			handle_at: None,
+			pressed_at: None,
 		}
 	}
 }
 
+/// What a repeating key is bound to, so letting go of that stops it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum RepeatSource {
+	Button(Button),
+	Combo(usize),
+	Sequence,
+	/// The stick's sector
+	Stick(usize),
+}
+
+/// A mapping that keeps getting pressed while its binding is held.
+#[derive(Debug)]
+struct Repeating {
+	source: RepeatSource,
+	map: StateMapping,
+	oidx: usize,
+	repeat: RepeatConfig,
+	next_at: Instant,
+}
+
 #[derive(Default, Debug, Clone)]
 struct AxisState {
 	value: f64,
 }
 
+/// What the stick did since it left the center.
+#[derive(Debug, Clone)]
+pub struct Stroke {
+	started: Instant,
+	pub path: Vec<StickDirection>,
+	/// Degrees turned, clockwise is positive
+	rotation: f64,
+	last_degrees: f64,
+}
+
+/// A word being swiped, or the words offered for the last one.
+#[derive(Debug, Clone)]
+pub struct SwipeState {
+	/// The stick swiping, the other one picks alternates
+	pub stick: usize,
+	/// Ring and sector of everything passed so far, empty once the swipe is done
+	path: Vec<(usize, usize)>,
+	pub candidates: Vec<String>,
+	pub selected: usize,
+	suffix: String,
+	/// Characters typed for the selected candidate, to erase when picking another
+	typed: usize,
+	/// The other stick has been at rest since the words came up, so pushing it picks one
+	rested: bool,
+	/// A word was picked during this push of the other stick already
+	picked: bool,
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct StickState {
 	pub degrees: f64,
 	pub value: f64,
-	pub sector: Option<usize>,
+	/// Ring and sector
+	pub sector: Option<(usize, usize)>,
+	/// A sector the stick moved to, and when it has settled enough to switch to it
+	pending_sector: Option<((usize, usize), Instant)>,
+	/// Held d-pad directions: left, up, right, down
+	pub dpad: [bool; 4],
+	pub stroke: Option<Stroke>,
+	/// The overlay of the handler the sector and d-pad directions are held for
+	oidx: Option<usize>,
 }
 
 #[derive(Debug)]
 pub struct CachedConfig {
 	pub config: GamepadConfig,
 	pub overlays: IndexMap<String, CachedOverlay>,
+	/// Swipe typing word lists by path
+	pub dictionaries: HashMap<String, Dictionary>,
+	/// Steno chords by path
+	pub chord_dictionaries: HashMap<String, ChordDictionary>,
 }
 
 #[derive(Debug)]
 pub struct State {
 	pub config: Arc<CachedConfig>,
 	pub current_overlays: Vec<usize>,
+	/// The overlay at the bottom, following the focused window
+	pub base_overlay: usize,
 	pub button_combos: IndexMap<Button, ButtonComboList>,
 	pub button_states: Vec<ButtonState>,
 	pub combo_states: Vec<bool>,
 	pub axis_states: Vec<AxisState>,
 	pub stick_states: Vec<StickState>,
 	active_maps: Vec<(usize, StateMapping)>,
+	/// Macros to cancel when their binding is released, with the id they were started under
+	running_macros: Vec<(StateMapping, u64)>,
+	next_macro_id: u64,
+	repeating: Vec<Repeating>,
+	pub swipe: Option<SwipeState>,
+	predictor: Option<Predictor>,
+	/// What's been typed of the current word, and how it might go on
+	pub typed_word: String,
+	pub suggestions: Vec<String>,
+	/// Buttons pressed for the steno chord being made
+	pub chord: Vec<Button>,
+	/// A combo that's down, waiting in case a bigger one is being pressed
+	pending_combo: Option<(usize, Instant)>,
+	/// Buttons of a sequence pressed so far, and until when the next one can come
+	pub sequence: Vec<Button>,
+	sequence_deadline: Option<Instant>,
+	/// Buttons taken by a sequence, their release goes nowhere
+	sequence_buttons: Vec<Button>,
 }
 
 impl State {
+	/// What's held and active, for the control socket.
+	pub fn query(&self) -> serde_json::Value {
+		let buttons: Vec<&'static str> =
+			Button::iter().filter(|btn| self.button_states[*btn as usize].down).map(|btn| btn.into()).collect();
+		let overlays: Vec<&String> =
+			self.current_overlays.iter().map(|oidx| self.config.overlays.get_index(*oidx).unwrap().0).collect();
+		let sticks: Vec<serde_json::Value> = self
+			.stick_states
+			.iter()
+			.map(|ss| json!({ "degrees": ss.degrees, "value": ss.value, "sector": ss.sector }))
+			.collect();
+		json!({
+			"buttons": buttons,
+			"overlays": overlays,
+			"baseOverlay": self.config.overlays.get_index(self.base_overlay).unwrap().0,
+			"sticks": sticks,
+			"sequence": self.sequence,
+			"chord": self.chord,
+		})
+	}
+
+	/// Active overlays from the top down, the first opaque one is the last.
+	fn visible_overlays<'a>(&'a self, config: &'a CachedConfig) -> impl Iterator<Item = usize> + 'a {
+		let mut blocked = false;
+		self.current_overlays.iter().rev().copied().take_while(move |oidx| {
+			let visible = !blocked;
+			blocked = config.overlays.index(*oidx).overlay.opaque;
+			visible
+		})
+	}
+
 	pub fn find_button_handler<'a>(
 		&self,
 		config: &'a CachedConfig,
 		btn: &Button,
 	) -> Option<(usize, &'a ButtonHandler)> {
-		self.current_overlays
-			.iter()
-			.rev()
-			.find_map(|oidx| config.overlays.index(*oidx).buttons.get(btn).map(|h| (*oidx, h)))
+		self.visible_overlays(config)
+			.find_map(|oidx| config.overlays.index(oidx).buttons.get(btn).map(|h| (oidx, h)))
 	}
 
 	pub fn find_combo_handler<'a>(&self, config: &'a CachedConfig, idx: usize) -> Option<(usize, &'a ButtonHandler)> {
-		self.current_overlays
-			.iter()
-			.rev()
-			.find_map(|oidx| config.overlays.index(*oidx).combos.get(&idx).map(|h| (*oidx, h)))
+		self.visible_overlays(config)
+			.find_map(|oidx| config.overlays.index(oidx).combos.get(&idx).map(|h| (oidx, h)))
 	}
 
 	pub fn find_stick_handler<'a>(&self, config: &'a CachedConfig, idx: usize) -> Option<(usize, &'a StickHandler)> {
-		self.current_overlays
+		self.visible_overlays(config)
+			.find_map(|oidx| config.overlays.index(oidx).sticks.get(&idx).map(|h| (oidx, h)))
+	}
+
+	/// Sequences from the active overlays that start with `prefix`, topmost overlay first.
+	fn find_sequences<'a>(
+		&self,
+		config: &'a CachedConfig,
+		prefix: &[Button],
+	) -> Vec<(usize, &'a CachedSequence)> {
+		self.visible_overlays(config)
+			.flat_map(|oidx| config.overlays.index(oidx).sequences.iter().map(move |seq| (oidx, seq)))
+			.filter(|(_, seq)| seq.buttons.starts_with(prefix))
+			.collect()
+	}
+
+	/// The buttons that would carry on the pending sequence, with what they'd end up doing.
+	pub fn sequence_continuations(&self, config: &CachedConfig) -> Vec<(Button, String)> {
+		if self.sequence.is_empty() {
+			return Vec::new();
+		}
+		self.find_sequences(config, &self.sequence)
 			.iter()
-			.rev()
-			.find_map(|oidx| config.overlays.index(*oidx).sticks.get(&idx).map(|h| (*oidx, h)))
+			.filter_map(|(_, seq)| {
+				let next = *seq.buttons.get(self.sequence.len())?;
+				let label = match (&seq.handler.label, &seq.handler.map) {
+					(Some(label), _) => label.clone(),
+					(None, Some(map)) if seq.buttons.len() == self.sequence.len() + 1 => map.label(),
+					_ => "...".to_string(),
+				};
+				Some((next, label))
+			})
+			.collect()
+	}
+
+	/// The steno settings of the overlay on top, if it has them.
+	pub fn find_steno<'a>(&self, config: &'a CachedConfig) -> Option<&'a StenoConfig> {
+		config.overlays.index(*self.current_overlays.last()?).overlay.steno.as_ref()
+	}
+
+	pub fn find_axis_handler<'a>(&self, config: &'a CachedConfig, axis: &Axis) -> Option<(usize, &'a AxisHandler)> {
+		self.visible_overlays(config)
+			.find_map(|oidx| config.overlays.index(oidx).axes.get(axis).map(|h| (oidx, h)))
 	}
 }
 
-pub fn state_task(
-	events: crossbeam_channel::Receiver<InputEvent>,
-	action_sender: crossbeam_channel::Sender<Action>,
-	ui_event_proxy: EventLoopProxy<UIEvent>,
-	main_events: crossbeam_channel::Receiver<MainEvent>,
-) -> Result<(), anyhow::Error> {
-	let config = read_to_string("configs/default.json5")?;
-	let config: GamepadConfig = json5::from_str(&config)?;
+/// Apply deadzone, curve and inversion to a -1.0 to 1.0 axis value.
+fn shape_axis(mapping: &PadAxisMapping, value: f64) -> f64 {
+	let magnitude = value.abs().min(1.0);
+	let shaped = if magnitude <= mapping.deadzone {
+		0.0
+	} else {
+		((magnitude - mapping.deadzone) / (1.0 - mapping.deadzone)).powf(mapping.curve)
+	};
+	let shaped = shaped.copysign(value);
+	if mapping.invert {
+		-shaped
+	} else {
+		shaped
+	}
+}
 
-	println!("{:?}", &config);
+const STICK_AXES: [(Axis, Axis); 2] = [(Axis::LeftX, Axis::LeftY), (Axis::RightX, Axis::RightY)];
 
-	let overlays: IndexMap<String, CachedOverlay> = IndexMap::from_iter(config.overlays.iter().map(|(id, overlay)| {
-		let buttons = IndexMap::from_iter(overlay.bindings.iter().filter_map(|b| match b {
-			Binding::Button { button, handler } => Some((*button, handler.clone())),
-			_ => None,
-		}));
-		let combos = IndexMap::from_iter(overlay.bindings.iter().filter_map(|b| match b {
-			Binding::Combo { combo, handler } => {
-				let idx = config.combos.get_index_of(combo);
-				match idx {
-					Some(idx) => Some((idx, handler.clone())),
-					None => {
-						println!("Combo not found: {}", combo);
-						None
+/// The sector `degrees` falls in, anything before the first one belongs to the last.
+fn sector_at(sectors: &[CircleSector], degrees: f64) -> Option<usize> {
+	sectors.iter().rposition(|s| s.from_degrees <= degrees).or(sectors.len().checked_sub(1))
+}
+
+/// Which of left, up, right and down a stick at `degrees` holds, given the ones it held so far.
+fn dpad_directions(dpad: &DpadHandler, degrees: f64, value: f64, held: [bool; 4]) -> [bool; 4] {
+	let mut active = [false; 4];
+	if value <= dpad.deadzone {
+		return active;
+	}
+	// In the same order as the angles, left is 0 degrees
+	let distances = [0., 90., 180., 270.].map(|at: f64| {
+		let distance = (degrees - at).rem_euclid(360.);
+		distance.min(360. - distance)
+	});
+	// However far a held direction's margin reaches, only the two nearest the stick can be held
+	let mut nearest = [0, 1, 2, 3];
+	nearest.sort_by(|a, b| distances[*a].partial_cmp(&distances[*b]).unwrap());
+	for i in nearest.into_iter().take(2) {
+		let reach = 45. + dpad.diagonal.clamp(0., 90.) / 2. + if held[i] { dpad.release_margin } else { 0. };
+		active[i] = distances[i] <= reach;
+	}
+	active
+}
+
+/// Where a sequence stands after a button press.
+#[derive(Debug, PartialEq)]
+enum SequenceStep {
+	/// The buttons pressed aren't the start of any sequence
+	None,
+	/// They are, and the next button has to come within this many ms
+	Pending(u64),
+	/// They make a sequence and no longer one starts with them
+	Complete,
+}
+
+/// How far the buttons in `pressed` get through any of `sequences`.
+fn sequence_step(sequences: &[(usize, &CachedSequence)], pressed: &[Button]) -> SequenceStep {
+	let matches: Vec<_> = sequences.iter().filter(|(_, seq)| seq.buttons.starts_with(pressed)).collect();
+	let longer = matches.iter().filter(|(_, seq)| seq.buttons.len() > pressed.len()).map(|(_, seq)| seq.timeout).max();
+	match longer {
+		_ if matches.is_empty() => SequenceStep::None,
+		Some(timeout) => SequenceStep::Pending(timeout),
+		None => SequenceStep::Complete,
+	}
+}
+
+/// How many degrees `degrees` is outside of sector `idx`, 0 when inside it.
+fn degrees_outside(sectors: &[CircleSector], idx: usize, degrees: f64) -> f64 {
+	if sectors.len() < 2 {
+		return 0.;
+	}
+	let from = sectors[idx].from_degrees;
+	let to = sectors.get(idx + 1).unwrap_or(&sectors[0]).from_degrees;
+	let width = (to - from).rem_euclid(360.);
+	let offset = (degrees - from).rem_euclid(360.);
+	if offset <= width {
+		0.
+	} else {
+		(offset - width).min(360. - offset)
+	}
+}
+
+/// An overlay's bindings after the ones of the overlays it extends and the templates it uses, later ones win.
+fn resolve_bindings(config: &GamepadConfig, id: &str, seen: &mut Vec<String>) -> Result<Vec<Binding>, anyhow::Error> {
+	if let Some(pos) = seen.iter().position(|other| other == id) {
+		if pos == seen.len() - 1 {
+			bail!("Overlay '{}' extends itself", id);
+		}
+		bail!("Overlays extend each other: {} -> {}", seen[pos..].join(" -> "), id);
+	}
+	let overlay = config.overlays.get(id).ok_or(anyhow!("Overlay '{}' not found", id))?;
+	seen.push(id.to_string());
+	let mut bindings = Vec::new();
+	for base in &overlay.extends {
+		bindings.extend(resolve_bindings(config, base, seen)?);
+	}
+	for used in &overlay.templates {
+		let template = config.templates.get(&used.template).ok_or(anyhow!("Template '{}' not found", used.template))?;
+		for binding in &template.bindings {
+			let binding = substitute_args(binding.clone(), &used.args);
+			bindings.push(
+				serde_json::from_value(binding)
+					.map_err(|e| anyhow!("Template '{}' in overlay '{}': {}", used.template, id, e))?,
+			);
+		}
+	}
+	bindings.extend(overlay.bindings.iter().cloned());
+	seen.pop();
+	Ok(bindings)
+}
+
+/// Replace `${name}` in every string of a template binding.
+fn substitute_args(value: serde_json::Value, args: &IndexMap<String, String>) -> serde_json::Value {
+	use serde_json::Value;
+	match value {
+		Value::String(s) => {
+			Value::String(args.iter().fold(s, |s, (name, arg)| s.replace(&format!("${{{}}}", name), arg)))
+		}
+		Value::Array(values) => Value::Array(values.into_iter().map(|v| substitute_args(v, args)).collect()),
+		Value::Object(map) => Value::Object(map.into_iter().map(|(k, v)| (k, substitute_args(v, args))).collect()),
+		other => other,
+	}
+}
+
+impl CachedConfig {
+	/// Resolve and check everything in the config, and load the dictionaries it uses.
+	pub fn new(config: GamepadConfig) -> Result<Self, anyhow::Error> {
+		let resolved = config
+			.overlays
+			.keys()
+			.map(|id| resolve_bindings(&config, id, &mut Vec::new()))
+			.collect::<Result<Vec<_>, _>>()?;
+
+		let overlays: IndexMap<String, CachedOverlay> = IndexMap::from_iter(config.overlays.iter().zip(&resolved).map(|((id, overlay), bindings)| {
+			let buttons = IndexMap::from_iter(bindings.iter().filter_map(|b| match b {
+				Binding::Button { button, handler } => Some((*button, handler.clone())),
+				_ => None,
+			}));
+			let combos = IndexMap::from_iter(bindings.iter().filter_map(|b| match b {
+				Binding::Combo { combo, handler } => {
+					let idx = config.combos.get_index_of(combo);
+					match idx {
+						Some(idx) => Some((idx, handler.clone())),
+						None => {
+							println!("Combo not found: {}", combo);
+							None
+						}
 					}
 				}
-			}
-			_ => None,
-		}));
-		let sticks = IndexMap::from_iter(overlay.bindings.iter().filter_map(|b| match b {
-			Binding::Stick { stick, handler } => {
-				let mut handler = handler.clone();
-				handler
-					.circle
-					.iter_mut()
-					.for_each(|ch| ch.sectors.sort_by(|a, b| a.from_degrees.partial_cmp(&b.from_degrees).unwrap()));
-				Some((*stick as usize, handler))
-			}
-			_ => None,
+				_ => None,
+			}));
+			let sticks = IndexMap::from_iter(bindings.iter().filter_map(|b| match b {
+				Binding::Stick { stick, handler } => {
+					let mut handler = StickHandler::clone(handler);
+					// Only `rings` is used from here on, ordered from the center out
+					if let Some(ch) = &mut handler.circle {
+						if !ch.sectors.is_empty() {
+							let sectors = std::mem::take(&mut ch.sectors);
+							ch.rings.push(CircleRing { min_value: ch.min_value, sectors });
+						}
+						ch.rings.sort_by(|a, b| a.min_value.partial_cmp(&b.min_value).unwrap());
+						for ring in &mut ch.rings {
+							ring.sectors.sort_by(|a, b| a.from_degrees.partial_cmp(&b.from_degrees).unwrap());
+						}
+					}
+					Some((*stick as usize, handler))
+				}
+				_ => None,
+			}));
+			let axes = IndexMap::from_iter(bindings.iter().filter_map(|b| match b {
+				Binding::Axis { axis, handler } => Some((*axis, handler.clone())),
+				_ => None,
+			}));
+			let sequences = bindings
+				.iter()
+				.filter_map(|b| match b {
+					Binding::Sequence { sequence, timeout, handler } if !sequence.is_empty() => {
+						Some(CachedSequence { buttons: sequence.clone(), timeout: *timeout, handler: handler.clone() })
+					}
+					_ => None,
+				})
+				.collect();
+			(
+				id.clone(),
+				CachedOverlay {
+					overlay: overlay.clone(),
+					buttons,
+					combos,
+					sticks,
+					axes,
+					sequences,
+				},
+			)
 		}));
-		(
-			id.clone(),
-			CachedOverlay {
-				overlay: overlay.clone(),
-				buttons,
-				combos,
-				sticks,
-			},
-		)
-	}));
 
-	let mut current_overlays = Vec::new();
-	let base_overlay = overlays
-		.get_index_of(&config.base_overlay)
-		.ok_or(anyhow!("Base overlay '{}' not found", &config.base_overlay))?;
-	current_overlays.push(base_overlay);
+		if !overlays.contains_key(&config.base_overlay) {
+			bail!("Base overlay '{}' not found", &config.base_overlay);
+		}
+		for rule in config.focus.iter().flat_map(|f| &f.rules) {
+			if !overlays.contains_key(&rule.overlay) {
+				bail!("Focus rule overlay '{}' not found", &rule.overlay);
+			}
+		}
+
+		let mut dictionaries = HashMap::new();
+		for handler in overlays.values().flat_map(|o| o.sticks.values()) {
+			let Some(swipe) = handler.circle.as_ref().and_then(|ch| ch.swipe.as_ref()) else { continue };
+			if dictionaries.contains_key(&swipe.dictionary) {
+				continue;
+			}
+			match Dictionary::load(&swipe.dictionary) {
+				Ok(dictionary) => {
+					dictionaries.insert(swipe.dictionary.clone(), dictionary);
+				}
+				Err(e) => println!("Dictionary {}: {:?}", swipe.dictionary, e),
+			}
+		}
+
+		let mut chord_dictionaries = HashMap::new();
+		for steno in overlays.values().filter_map(|o| o.overlay.steno.as_ref()) {
+			if chord_dictionaries.contains_key(&steno.dictionary) {
+				continue;
+			}
+			match ChordDictionary::load(&steno.dictionary) {
+				Ok(dictionary) => {
+					chord_dictionaries.insert(steno.dictionary.clone(), dictionary);
+				}
+				Err(e) => println!("Dictionary {}: {:?}", steno.dictionary, e),
+			}
+		}
+
+		Ok(Self { config, overlays, dictionaries, chord_dictionaries })
+	}
+}
+
+/// Has the actions thread let go of everything when the state thread panics, which skips `release_all`.
+struct ReleaseGuard(crossbeam_channel::Sender<Action>);
+
+impl Drop for ReleaseGuard {
+	fn drop(&mut self) {
+		if std::thread::panicking() {
+			let _ = self.0.send(Action::ReleaseAll);
+		}
+	}
+}
+
+/// Why the state thread stopped, it's started again with the new config after a reload.
+pub enum StateExit {
+	Quit,
+	/// The window that had the focus, as the focus thread only says when it changes
+	Reload(Box<CachedConfig>, Option<FocusedWindow>),
+}
+
+pub fn state_task(
+	events: crossbeam_channel::Receiver<InputEvent>,
+	action_sender: crossbeam_channel::Sender<Action>,
+	ui_event_proxy: EventLoopProxy<UIEvent>,
+	main_events: crossbeam_channel::Receiver<MainEvent>,
+	config: CachedConfig,
+	config_path: &str,
+	mut focused: Option<FocusedWindow>,
+) -> Result<StateExit, anyhow::Error> {
+	println!("{:?}", &config.config);
+	let _release_guard = ReleaseGuard(action_sender.clone());
+
+	let base_overlay = config.overlays.get_index_of(&config.config.base_overlay).unwrap();
+	let current_overlays = vec![base_overlay];
 
 	let mut button_combos = IndexMap::new();
-	for (idx, combo) in config.combos.values().enumerate() {
+	for (idx, combo) in config.config.combos.values().enumerate() {
 		for btn in &combo.buttons {
 			if !button_combos.contains_key(btn) {
 				button_combos.insert(*btn, ButtonComboList::default());
@@ -180,17 +553,30 @@ pub fn state_task(
 	let axis_states = vec![AxisState::default(); Axis::iter().len()];
 	let stick_states = vec![StickState::default(); 2];
 
-	let cached_config = Arc::new(CachedConfig { config, overlays });
+	let cached_config = Arc::new(config);
 
 	let state_arc = Arc::new(RwLock::new(State {
 		config: cached_config.clone(),
 		current_overlays,
+		base_overlay,
 		button_combos,
 		button_states,
 		combo_states,
 		axis_states,
 		stick_states,
 		active_maps: Vec::new(),
+		running_macros: Vec::new(),
+		next_macro_id: 0,
+		repeating: Vec::new(),
+		swipe: None,
+		predictor: cached_config.config.prediction.as_ref().map(Predictor::new),
+		typed_word: String::new(),
+		suggestions: Vec::new(),
+		chord: Vec::new(),
+		pending_combo: None,
+		sequence: Vec::new(),
+		sequence_deadline: None,
+		sequence_buttons: Vec::new(),
 	}));
 
 	let print_state = |state: &State| {
@@ -214,7 +600,24 @@ pub fn state_task(
 		match idx {
 			Some(idx) => {
 				if !state.current_overlays.contains(&idx) {
-					state.current_overlays.push(idx);
+					let overlay = &cached_config.overlays.index(idx).overlay;
+					let base = state.base_overlay;
+					// Only one overlay of a group at a time, the base overlay stays whatever its group
+					if let Some(group) = &overlay.group {
+						state.current_overlays.retain(|other| {
+							*other == base || cached_config.overlays.index(*other).overlay.group.as_ref() != Some(group)
+						});
+					}
+					// On top of everything with the same or a lower priority, and never below the base overlay
+					let above_base =
+						state.current_overlays.iter().position(|other| *other == base).map_or(0, |pos| pos + 1);
+					let pos = state
+						.current_overlays
+						.iter()
+						.rposition(|other| cached_config.overlays.index(*other).overlay.priority <= overlay.priority)
+						.map_or(0, |pos| pos + 1)
+						.max(above_base);
+					state.current_overlays.insert(pos, idx);
 				}
 			}
 			None => {
@@ -235,6 +638,32 @@ pub fn state_task(
 		}
 	};
 
+	// Swap the base overlay for another one, whatever is on top stays.
+	let set_base_overlay = |state: &mut State, idx: usize| {
+		if idx == state.base_overlay {
+			return;
+		}
+		let old = state.base_overlay;
+		state.base_overlay = idx;
+		match state.current_overlays.iter().position(|oidx| *oidx == old) {
+			Some(pos) if !state.current_overlays.contains(&idx) => state.current_overlays[pos] = idx,
+			Some(pos) => {
+				state.current_overlays.remove(pos);
+			}
+			None if !state.current_overlays.contains(&idx) => state.current_overlays.insert(0, idx),
+			None => {}
+		}
+	};
+	let focus_changed = |state: &mut State, window: &FocusedWindow| {
+		let mut rules = cached_config.config.focus.iter().flat_map(|f| &f.rules);
+		let name = match rules.find(|rule| rule.matches(window)) {
+			Some(rule) => &rule.overlay,
+			None => &cached_config.config.base_overlay,
+		};
+		println!("focus {:?} {:?} {:?} -> {}", window.instance, window.class, window.title, name);
+		set_base_overlay(state, cached_config.overlays.get_index_of(name).unwrap());
+	};
+
 	let trigger_mapping = |state: &mut State, map: &StateMapping, down: bool, oidx| {
 		match map {
 			StateMapping::Key(key) => {
@@ -252,18 +681,159 @@ pub fn state_task(
 					remove_overlay(state, name);
 				}
 			}
+			StateMapping::Text(text) => {
+				if down {
+					action_sender.send(Action::Text(text.clone())).unwrap();
+				}
+			}
+			StateMapping::Chord { modifiers, key } => {
+				if down {
+					for key in modifiers.iter().chain([key]) {
+						action_sender.send(Action::KeyDown(*key)).unwrap();
+					}
+				} else {
+					for key in [key].into_iter().chain(modifiers.iter().rev()) {
+						action_sender.send(Action::KeyUp(*key)).unwrap();
+					}
+				}
+			}
+			StateMapping::Char(c) => {
+				action_sender
+					.send(match down {
+						true => Action::CharDown(*c),
+						false => Action::CharUp(*c),
+					})
+					.unwrap();
+			}
+			StateMapping::Exec(exec) => {
+				if down {
+					action_sender.send(Action::Exec(exec.clone())).unwrap();
+				}
+			}
+			StateMapping::Suggestion(idx) => {
+				if let (true, Some(word), Some(config)) =
+					(down, state.suggestions.get(*idx), &cached_config.config.prediction)
+				{
+					let rest: String = word.chars().skip(state.typed_word.chars().count()).collect();
+					action_sender.send(Action::Text(format!("{}{}", rest, config.suffix))).unwrap();
+				}
+			}
+			StateMapping::Macro(mapping) => {
+				if down {
+					let id = state.next_macro_id;
+					state.next_macro_id += 1;
+					action_sender.send(Action::Macro(id, mapping.steps.clone())).unwrap();
+					if mapping.cancel_on_release {
+						state.running_macros.push((map.clone(), id));
+					}
+				} else if let Some(pos) = state.running_macros.iter().position(|(other, _)| *other == *map) {
+					let (_, id) = state.running_macros.remove(pos);
+					action_sender.send(Action::CancelMacro(id)).unwrap();
+				}
+			}
 		}
 		if down {
 			state.active_maps.push((oidx, map.clone()));
-		} else {
-			state.active_maps.retain(|(oidx, other)| *other != *map);
+		} else if let Some(pos) = state
+			.active_maps
+			.iter()
+			.position(|(other_oidx, other)| *other_oidx == oidx && *other == *map)
+			.or_else(|| state.active_maps.iter().position(|(_, other)| *other == *map))
+		{
+			// Only one of them, the same mapping may be held from elsewhere too
+			state.active_maps.remove(pos);
 		}
 	};
 
-	let trigger_handler = |state: &mut State, handler: &ButtonHandler, down: bool, oidx| {
+	// Let go of what's held from overlays that are no longer active, e.g. ones another of their group pushed out.
+	let release_removed_overlays = |state: &mut State| {
+		while let Some((oidx, map)) =
+			state.active_maps.iter().find(|(oidx, _)| !state.current_overlays.contains(oidx)).cloned()
+		{
+			trigger_mapping(state, &map, false, oidx);
+		}
+		// A stick whose handler went with its overlay holds nothing now, and starts over if it comes back
+		for (stick, s) in state.stick_states.iter_mut().enumerate() {
+			if s.oidx.is_some_and(|oidx| !state.current_overlays.contains(&oidx)) {
+				(s.sector, s.pending_sector, s.dpad, s.oidx) = (None, None, [false; 4], None);
+				state.repeating.retain(|r| r.source != RepeatSource::Stick(stick));
+			}
+		}
+	};
+
+	let tap_mapping = |state: &mut State, map: &StateMapping, oidx| {
+		trigger_mapping(state, map, true, oidx);
+		trigger_mapping(state, map, false, oidx);
+	};
+
+	// Undo every active mapping and go back to just the base overlay, e.g. when the controller goes away.
+	let release_all = |state: &mut State| {
+		for (oidx, map) in state.active_maps.clone().iter().rev() {
+			trigger_mapping(state, map, false, *oidx);
+		}
+		state.active_maps.clear();
+		state.running_macros.clear();
+		state.repeating.clear();
+		state.swipe = None;
+		state.chord.clear();
+		state.pending_combo = None;
+		state.sequence.clear();
+		state.sequence_deadline = None;
+		state.sequence_buttons.clear();
+		state.current_overlays = vec![state.base_overlay];
+		state.button_states.fill(ButtonState::default());
+		state.combo_states.fill(false);
+		state.axis_states.fill(AxisState::default());
+		state.stick_states.fill(StickState::default());
+		action_sender.send(Action::ReleaseAll).unwrap();
+	};
+
+	// Before leaving for good or for a reload
+	let shut_down = |state: &mut State| {
+		release_all(state);
+		if let Some(predictor) = &mut state.predictor {
+			predictor.save();
+		}
+	};
+
+	// Like trigger_mapping, but starts or stops repeating the mapping too if it's a key.
+	let trigger_repeating = |state: &mut State,
+	                         source: RepeatSource,
+	                         map: &StateMapping,
+	                         repeat: &Option<RepeatConfig>,
+	                         down: bool,
+	                         oidx| {
+		let repeat = repeat.as_ref().filter(|_| matches!(map, StateMapping::Key(_)));
+		if down {
+			trigger_mapping(state, map, true, oidx);
+			if let Some(repeat) = repeat {
+				if repeat.mode == RepeatMode::Turbo {
+					trigger_mapping(state, map, false, oidx);
+				}
+				state.repeating.push(Repeating {
+					source,
+					map: map.clone(),
+					oidx,
+					repeat: repeat.clone(),
+					next_at: Instant::now() + Duration::from_millis(repeat.delay),
+				});
+			}
+			return;
+		}
+		let mode = match state.repeating.iter().position(|r| r.source == source) {
+			Some(pos) => Some(state.repeating.remove(pos).repeat.mode),
+			None => repeat.map(|r| r.mode),
+		};
+		// A turbo mapping is already released after every press
+		if mode != Some(RepeatMode::Turbo) {
+			trigger_mapping(state, map, false, oidx);
+		}
+	};
+
+	let trigger_handler = |state: &mut State, source: RepeatSource, handler: &ButtonHandler, down: bool, oidx| {
 		// println!("trigger_handler {:?}", handler);
 		if let Some(map) = &handler.map {
-			trigger_mapping(state, &map, down, oidx);
+			trigger_repeating(state, source, map, &handler.repeat, down, oidx);
 		}
 	};
 
@@ -278,33 +848,100 @@ pub fn state_task(
 			s.handle_at = None;
 			s.in_combo = None;
 		}
-		if let Some((oidx, handler)) = state.find_combo_handler(&cached_config, idx) {
-			trigger_handler(state, &handler, false, oidx)
+		let found = state.find_combo_handler(&cached_config, idx);
+		publish(StateEvent::Combo {
+			combo: cached_config.config.combos.get_index(idx).unwrap().0.clone(),
+			down: false,
+			overlay: found.map(|(oidx, _)| cached_config.overlays.get_index(oidx).unwrap().0.clone()),
+			map: found.and_then(|(_, handler)| handler.map.clone()),
+		});
+		if let Some((oidx, handler)) = found {
+			trigger_handler(state, RepeatSource::Combo(idx), &handler, false, oidx)
+		}
+	};
+
+	let combo_down = |state: &mut State, idx: usize| {
+		let combo = cached_config.config.combos.index(idx);
+		println!("combo down {:?}", combo);
+		state.combo_states[idx] = true;
+		if let Some((pending, _)) = state.pending_combo {
+			let pending = cached_config.config.combos.index(pending);
+			if pending.buttons.iter().any(|btn| combo.buttons.contains(btn)) {
+				state.pending_combo = None;
+			}
+		}
+		for btn in &combo.buttons {
+			let s = &mut state.button_states[*btn as usize];
+			s.handled = true;
+			s.handle_at = None;
+			s.in_combo = Some(idx);
+		}
+		let found = state.find_combo_handler(&cached_config, idx);
+		publish(StateEvent::Combo {
+			combo: cached_config.config.combos.get_index(idx).unwrap().0.clone(),
+			down: true,
+			overlay: found.map(|(oidx, _)| cached_config.overlays.get_index(oidx).unwrap().0.clone()),
+			map: found.and_then(|(_, handler)| handler.map.clone()),
+		});
+		if let Some((oidx, handler)) = found {
+			trigger_handler(state, RepeatSource::Combo(idx), &handler, true, oidx)
 		}
 	};
 
+	// A button of a down combo was pressed or released again.
+	let combo_button = |state: &mut State, btn: Button, idx: usize, down: bool| {
+		let combo = cached_config.config.combos.index(idx);
+		state.button_states[btn as usize].handled = true;
+		if down {
+			// Back into a combo that's held until all its buttons are up
+			return;
+		}
+		if combo.release_mode == ComboRelease::All && combo.buttons.iter().any(|b| state.button_states[*b as usize].down) {
+			return;
+		}
+		combo_up(state, idx);
+	};
+
 	let check_combos = |state: &mut State, btn: Button| {
-		let combos = state.button_combos.get(&btn);
-		if let Some(combos) = combos {
-			let combo = combos.combos.iter().find(|(idx, combo)| {
-				let all_down = combo.buttons.iter().all(|btn| state.button_states[*btn as usize].down);
-				return all_down && !state.combo_states[*idx];
-			});
-			if let Some((idx, combo)) = combo {
-				println!("combo down {:?}", combo);
-				state.combo_states[*idx] = true;
-				for btn in &combo.buttons {
-					let s = &mut state.button_states[*btn as usize];
-					s.handled = true;
-					s.handle_at = None;
-					s.in_combo = Some(*idx);
-				}
-				if let Some((oidx, handler)) = state.find_combo_handler(&cached_config, *idx) {
-					trigger_handler(state, &handler, true, oidx)
+		let Some(combos) = state.button_combos.get(&btn) else { return };
+		// Buttons that went to their own bindings already can't be part of a combo
+		let available = |b: &Button| {
+			let s = &state.button_states[*b as usize];
+			s.down && !s.handled
+		};
+		let in_order = |combo: &ButtonCombo| {
+			!combo.strict_order
+				|| combo.buttons.windows(2).all(|w| {
+					state.button_states[w[0] as usize].pressed_at <= state.button_states[w[1] as usize].pressed_at
+				})
+		};
+		let best = combos
+			.combos
+			.iter()
+			.filter(|(idx, combo)| !state.combo_states[*idx] && combo.buttons.iter().all(available) && in_order(combo))
+			.max_by_key(|(_, combo)| (combo.priority, combo.buttons.len()));
+		let Some((idx, combo)) = best else { return };
+		let wait = combos
+			.combos
+			.iter()
+			.filter(|(other_idx, other)| {
+				!state.combo_states[*other_idx]
+					&& other.buttons.len() > combo.buttons.len()
+					&& other.priority >= combo.priority
+					&& combo.buttons.iter().all(|b| other.buttons.contains(b))
+			})
+			.map(|(_, other)| other.timeout)
+			.max();
+		let idx = *idx;
+		match wait {
+			Some(timeout) => {
+				for b in &combo.buttons {
+					state.button_states[*b as usize].handle_at = None;
 				}
+				state.pending_combo = Some((idx, Instant::now() + Duration::from_millis(timeout)));
 			}
+			None => combo_down(state, idx),
 		}
-		false
 	};
 
 	let do_handle_button = |mut state: &mut State, btn: Button, down: bool| {
@@ -312,34 +949,170 @@ pub fn state_task(
 		let btn_state = state.button_states.get_mut(btn as usize).unwrap();
 		btn_state.handled = true;
 		btn_state.handle_at = None;
-		if let Some((oidx, handler)) = state.find_button_handler(&cached_config, &btn) {
-			trigger_handler(state, &handler, down, oidx)
+		let found = state.find_button_handler(&cached_config, &btn);
+		publish(StateEvent::Button {
+			button: btn,
+			down,
+			overlay: found.map(|(oidx, _)| cached_config.overlays.get_index(oidx).unwrap().0.clone()),
+			map: found.and_then(|(_, handler)| handler.map.clone()),
+		});
+		if let Some((oidx, handler)) = found {
+			trigger_handler(state, RepeatSource::Button(btn), &handler, down, oidx)
+		}
+	};
+
+	// Collect chord buttons while a steno overlay is on top, returns whether the button was taken.
+	let handle_steno = |state: &mut State, btn: Button, down: bool| -> bool {
+		let Some(steno) = state.find_steno(&cached_config) else { return false };
+		let in_chord = steno.buttons.as_ref().is_none_or(|buttons| buttons.contains(&btn));
+		// A button held since before the chord started lets go of its own binding
+		if !in_chord || (!down && !state.chord.contains(&btn)) {
+			return false;
+		}
+		let s = &mut state.button_states[btn as usize];
+		s.down = down;
+		s.handled = true;
+		s.handle_at = None;
+		if down {
+			if !state.chord.contains(&btn) {
+				state.chord.push(btn);
+			}
+			return true;
+		}
+		if state.chord.iter().any(|b| state.button_states[*b as usize].down) {
+			return true;
+		}
+		let chord = std::mem::take(&mut state.chord);
+		match cached_config.chord_dictionaries.get(&steno.dictionary).and_then(|d| d.lookup(&chord)) {
+			Some(text) => action_sender.send(Action::Text(format!("{}{}", text, steno.suffix))).unwrap(),
+			None => println!("No chord {:?}", chord),
+		}
+		true
+	};
+
+	// A sequence that went nowhere gives its buttons back to their own bindings. Those still held are pending again
+	// like any other pressed button, so they can still be part of a combo.
+	let replay_sequence = |state: &mut State, sequence: &[Button]| {
+		for (i, btn) in sequence.iter().enumerate() {
+			let held = state.button_states[*btn as usize].down && !sequence[i + 1..].contains(btn);
+			if !held {
+				do_handle_button(state, *btn, true);
+				do_handle_button(state, *btn, false);
+				continue;
+			}
+			state.sequence_buttons.retain(|b| b != btn);
+			let wait = state.button_combos.get(btn).map(|combos| Duration::from_millis(combos.max_timeout));
+			let s = &mut state.button_states[*btn as usize];
+			s.handled = false;
+			match wait {
+				Some(wait) => s.handle_at = Some(s.pressed_at.unwrap_or_else(Instant::now) + wait),
+				None => do_handle_button(state, *btn, true),
+			}
 		}
 	};
 
+	// Trigger the sequence pressed so far, if it is one, and start over.
+	let finish_sequence = |state: &mut State| {
+		let sequence = std::mem::take(&mut state.sequence);
+		state.sequence_deadline = None;
+		let found = state.find_sequences(&cached_config, &sequence).into_iter().find(|(_, seq)| seq.buttons == sequence);
+		match found {
+			Some((oidx, seq)) => {
+				println!("sequence {:?}", sequence);
+				trigger_handler(state, RepeatSource::Sequence, &seq.handler, true, oidx);
+				trigger_handler(state, RepeatSource::Sequence, &seq.handler, false, oidx);
+			}
+			None => replay_sequence(state, &sequence),
+		}
+	};
+
+	// Follow button sequences, returns whether the button was taken by one.
+	let handle_sequence = |state: &mut State, btn: Button, down: bool| -> bool {
+		if !down {
+			let Some(pos) = state.sequence_buttons.iter().position(|b| *b == btn) else { return false };
+			state.sequence_buttons.remove(pos);
+			state.button_states[btn as usize].down = false;
+			return true;
+		}
+		let sequences = state.find_sequences(&cached_config, &[]);
+		let mut prefix = state.sequence.clone();
+		prefix.push(btn);
+		let mut step = sequence_step(&sequences, &prefix);
+		if step == SequenceStep::None && !state.sequence.is_empty() {
+			// One that went wrong, the button may still start another
+			let sequence = std::mem::take(&mut state.sequence);
+			state.sequence_deadline = None;
+			replay_sequence(state, &sequence);
+			prefix = vec![btn];
+			step = sequence_step(&sequences, &prefix);
+		}
+		let timeout = match step {
+			SequenceStep::None => return false,
+			SequenceStep::Pending(timeout) => Some(timeout),
+			SequenceStep::Complete => None,
+		};
+		let s = &mut state.button_states[btn as usize];
+		s.down = true;
+		s.handled = true;
+		s.handle_at = None;
+		s.pressed_at = Some(Instant::now());
+		state.sequence_buttons.push(btn);
+		state.sequence = prefix;
+		match timeout {
+			Some(timeout) => state.sequence_deadline = Some(Instant::now() + Duration::from_millis(timeout)),
+			// Nothing longer to wait for
+			None => finish_sequence(state),
+		}
+		true
+	};
+
 	let maybe_handle_button = |mut state: &mut State, btn: Button, down: bool| {
 		let prev_state = state.button_states.get(btn as usize).unwrap();
 		if prev_state.down == down {
 			return;
 		}
 
+		if handle_steno(state, btn, down) || handle_sequence(state, btn, down) {
+			return;
+		}
+
+		// Letting go of a combo that was waiting for a bigger one means the smaller one was meant
+		if let Some((idx, _)) = state.pending_combo {
+			if !down && cached_config.config.combos.index(idx).buttons.contains(&btn) {
+				state.pending_combo = None;
+				combo_down(state, idx);
+			}
+		}
+
+		let prev_state = state.button_states.get(btn as usize).unwrap();
 		if !prev_state.handled {
 			do_handle_button(state, btn, !down);
 		}
 
+		// Any button means the last swiped word is fine as it is
+		if down && state.swipe.as_ref().is_some_and(|s| s.path.is_empty()) {
+			state.swipe = None;
+		}
+
 		let prev_state = state.button_states.get(btn as usize).unwrap().clone();
 
 		{
 			let s = state.button_states.get_mut(btn as usize).unwrap();
 			s.down = down;
 			s.handled = false;
+			if down {
+				s.pressed_at = Some(Instant::now());
+			}
 		}
 
 		if let Some(idx) = prev_state.in_combo {
-			combo_up(state, idx);
+			combo_button(state, btn, idx, down);
 		} else if down && state.button_combos.contains_key(&btn) {
-			let handled = check_combos(state, btn);
-			if !handled {
+			check_combos(state, btn);
+			let pending = state
+				.pending_combo
+				.is_some_and(|(idx, _)| cached_config.config.combos.index(idx).buttons.contains(&btn));
+			if !state.button_states[btn as usize].handled && !pending {
 				let combos = state.button_combos.get(&btn).unwrap();
 				let s = state.button_states.get_mut(btn as usize).unwrap();
 				s.handle_at = Some(Instant::now() + Duration::from_millis(combos.max_timeout));
@@ -349,6 +1122,111 @@ pub fn state_task(
 		}
 	};
 
+	// Follow a swipe through the sectors, and type the best word when the stick is back in the center.
+	let update_swipe = |state: &mut State, stick: usize, rings: &[CircleRing], swipe: &SwipeConfig, sector| {
+		let Some(sector) = sector else {
+			let Some(s) = state.swipe.as_mut().filter(|s| s.stick == stick && !s.path.is_empty()) else { return };
+			let path = std::mem::take(&mut s.path);
+			let keys: HashMap<char, (usize, usize)> = rings
+				.iter()
+				.enumerate()
+				.flat_map(|(r, ring)| ring.sectors.iter().enumerate().map(move |(i, sec)| ((r, i), sec)))
+				.flat_map(|(key, sec)| sec.letters.iter().flat_map(|l| l.chars()).flat_map(char::to_lowercase).map(move |c| (c, key)))
+				.collect();
+			s.candidates = match cached_config.dictionaries.get(&swipe.dictionary) {
+				Some(dictionary) => dictionary.swipe_matches(&keys, &path, swipe.alternates + 1),
+				None => Vec::new(),
+			};
+			s.selected = 0;
+			s.suffix = swipe.suffix.clone();
+			s.typed = 0;
+			if let Some(word) = s.candidates.first() {
+				let text = format!("{}{}", word, s.suffix);
+				s.typed = text.chars().count();
+				action_sender.send(Action::Text(text)).unwrap();
+			}
+			return;
+		};
+		let s = match &mut state.swipe {
+			Some(s) if s.stick == stick && !s.path.is_empty() => s,
+			// A new swipe, forget the words offered for the last one
+			_ => state.swipe.insert(SwipeState {
+				stick,
+				path: Vec::new(),
+				candidates: Vec::new(),
+				selected: 0,
+				suffix: String::new(),
+				typed: 0,
+				rested: false,
+				picked: false,
+			}),
+		};
+		if s.path.last() != Some(&sector) {
+			s.path.push(sector);
+		}
+	};
+
+	// With words on offer, the other stick picks between them: up or left for the previous one, down or right for the next.
+	// Returns whether the stick is being pushed to pick, a push that started before the words came up isn't one.
+	let pick_alternate = |state: &mut State, stick: usize, degrees: f64, value: f64| -> bool {
+		let Some(s) = state.swipe.as_mut().filter(|s| s.stick != stick && s.candidates.len() > 1) else { return false };
+		if value < 0.3 {
+			s.rested = true;
+			s.picked = false;
+			return false;
+		}
+		if !s.rested {
+			return false;
+		}
+		if value > 0.6 && !s.picked {
+			s.picked = true;
+			let count = s.candidates.len();
+			s.selected = if (135. ..315.).contains(&degrees) { (s.selected + 1) % count } else { (s.selected + count - 1) % count };
+			// Erased in line with the text, which may still be being typed, and just as slowly
+			let delay = cached_config.config.output.text.key_delay;
+			let mut steps = Vec::new();
+			for _ in 0..s.typed {
+				steps.push(MacroStep::Tap(Key::Backspace));
+				if delay > 0 {
+					steps.push(MacroStep::Delay(delay));
+				}
+			}
+			let text = format!("{}{}", s.candidates[s.selected], s.suffix);
+			s.typed = text.chars().count();
+			steps.push(MacroStep::Text(text));
+			action_sender.send(Action::Type(steps)).unwrap();
+		}
+		true
+	};
+
+	// Follow what the actions thread typed and suggest how the current word could go on.
+	let update_typed = |state: &mut State, ev: MainEvent| {
+		let (Some(predictor), Some(config)) = (&mut state.predictor, &cached_config.config.prediction) else { return };
+		let word = &mut state.typed_word;
+		let mut finish_word = |word: &mut String| {
+			if word.chars().count() >= 2 && word.chars().any(char::is_alphabetic) {
+				predictor.learn(word);
+			}
+			word.clear();
+		};
+		match ev {
+			MainEvent::Typed(text) => {
+				for c in text.chars() {
+					if c.is_alphanumeric() || c == '\'' {
+						word.push(c);
+					} else {
+						finish_word(word);
+					}
+				}
+			}
+			MainEvent::Backspace => {
+				word.pop();
+			}
+			_ => finish_word(word),
+		}
+		state.suggestions = if word.is_empty() { Vec::new() } else { predictor.complete(word, config.suggestions) };
+	};
+
 	let update_stick = |state: &mut State, stick: usize, x: f64, y: f64| {
 		let s = &mut state.stick_states[stick];
 		let degrees = (y.atan2(x) + std::f64::consts::PI).to_degrees();
@@ -360,34 +1238,128 @@ pub fn state_task(
 
 		// println!("stick {} {:>7.1} {:>6.2}", stick, degrees, value);
 
+		// To its own handlers a stick picking a word is at rest, so whatever they hold is let go
+		let (x, y, value) = if pick_alternate(state, stick, degrees, value) { (0., 0., 0.) } else { (x, y, value) };
+
 		if let Some((oidx, handler)) = state.find_stick_handler(&cached_config, stick) {
+			state.stick_states[stick].oidx = Some(oidx);
+			if let Some(abs) = &handler.absolute {
+				let (x, y) = if value > abs.deadzone { (x.clamp(-1., 1.), y.clamp(-1., 1.)) } else { (0., 0.) };
+				action_sender
+					.send(Action::PointerMove(
+						abs.region.x + abs.region.width * (x + 1.) / 2.,
+						abs.region.y + abs.region.height * (y + 1.) / 2.,
+					))
+					.unwrap();
+			}
 			if let Some(ch) = &handler.circle {
-				let sector = if value > ch.min_value {
-					ch.sectors
-						.iter()
-						.enumerate()
-						.rfind(|(i, s)| s.from_degrees <= degrees)
-						.or_else(|| ch.sectors.iter().enumerate().last())
-				} else {
-					None
+				let raw_idx = ch
+					.rings
+					.iter()
+					.rposition(|ring| ring.min_value < value)
+					.and_then(|r| Some((r, sector_at(&ch.rings[r].sectors, degrees)?)));
+				// Stay in the current sector until the stick is clearly out of it
+				let candidate = match prev_sector_idx {
+					Some((r, prev))
+						if ch.rings.get(r).is_some_and(|ring| {
+							prev < ring.sectors.len()
+								&& value > ring.min_value - ch.hysteresis_value
+								&& ch.rings.get(r + 1).is_none_or(|next| value < next.min_value + ch.hysteresis_value)
+								&& degrees_outside(&ring.sectors, prev, degrees) <= ch.hysteresis_degrees
+						}) =>
+					{
+						Some((r, prev))
+					}
+					_ => raw_idx,
+				};
+				let s = &mut state.stick_states[stick];
+				let sector_idx = match candidate {
+					Some(idx) if candidate != prev_sector_idx && ch.settle_ms > 0 => match s.pending_sector {
+						Some((pending, at)) if pending == idx => {
+							if at <= Instant::now() { candidate } else { prev_sector_idx }
+						}
+						_ => {
+							s.pending_sector = Some((idx, Instant::now() + Duration::from_millis(ch.settle_ms)));
+							prev_sector_idx
+						}
+					},
+					_ => candidate,
 				};
-				let sector_idx = sector.map(|(i, _)| i);
-				let sector = sector.map(|(_, s)| s);
-				state.stick_states[stick].sector = sector_idx;
+				if sector_idx == candidate {
+					s.pending_sector = None;
+				}
+				let sector = sector_idx.and_then(|(r, i)| ch.rings[r].sectors.get(i));
+				s.sector = sector_idx;
 
-				if sector_idx != prev_sector_idx {
+				if let Some(swipe) = &ch.swipe {
+					if sector_idx != prev_sector_idx {
+						update_swipe(state, stick, &ch.rings, swipe, sector_idx);
+					}
+				} else if sector_idx != prev_sector_idx {
 					// println!("stick {} {:>7.1} {:>6.2} {:?} {:?}", stick, degrees, value, sector_idx, sector);
-					if let Some(psidx) = prev_sector_idx {
-						let prev_sector = &ch.sectors[psidx];
+					let prev_sector = prev_sector_idx.and_then(|(r, i)| ch.rings.get(r)?.sectors.get(i));
+					if let Some(prev_sector) = prev_sector {
 						if let Some(map) = prev_sector.map.clone() {
-							trigger_mapping(state, &map, false, oidx);
+							trigger_repeating(state, RepeatSource::Stick(stick), &map, &prev_sector.repeat, false, oidx);
 						}
 					}
 					if let Some(sector) = sector {
 						if let Some(map) = sector.map.clone() {
-							trigger_mapping(state, &map, true, oidx);
+							trigger_repeating(state, RepeatSource::Stick(stick), &map, &sector.repeat, true, oidx);
+						}
+					}
+				}
+			}
+			if let Some(dpad) = &handler.dpad {
+				// In the same order as the angles, left is 0 degrees
+				let maps = [&dpad.left, &dpad.up, &dpad.right, &dpad.down];
+				let held = state.stick_states[stick].dpad;
+				let active = dpad_directions(dpad, degrees, value, held);
+				state.stick_states[stick].dpad = active;
+				// Let go of directions before pressing new ones
+				for down in [false, true] {
+					for i in 0..4 {
+						if active[i] != held[i] && active[i] == down {
+							if let Some(map) = maps[i] {
+								trigger_mapping(state, map, down, oidx);
+							}
+						}
+					}
+				}
+			}
+			if let Some(gh) = &handler.gestures {
+				let now = Instant::now();
+				let s = &mut state.stick_states[stick];
+				if value > gh.threshold {
+					let dir = StickDirection::from_degrees(degrees);
+					match &mut s.stroke {
+						Some(stroke) => {
+							stroke.rotation += (degrees - stroke.last_degrees + 180.).rem_euclid(360.) - 180.;
+							stroke.last_degrees = degrees;
+							if stroke.path.last() != Some(&dir) {
+								stroke.path.push(dir);
+							}
+						}
+						None => {
+							s.stroke = Some(Stroke { started: now, path: vec![dir], rotation: 0., last_degrees: degrees });
 						}
 					}
+				} else if let Some(stroke) = s.stroke.take() {
+					// Spins win over flicks, which win over plain paths
+					let turns = (stroke.rotation.abs() / 360.) as u32;
+					let mut candidates = Vec::new();
+					if turns > 0 {
+						candidates.push(if stroke.rotation > 0. { Gesture::Clockwise(turns) } else { Gesture::CounterClockwise(turns) });
+					}
+					if stroke.path.len() == 1 && now - stroke.started <= Duration::from_millis(gh.flick_ms) {
+						candidates.push(Gesture::Flick(stroke.path[0]));
+					}
+					candidates.push(Gesture::Path(stroke.path));
+					let found = candidates.iter().find_map(|g| gh.gestures.iter().find(|b| b.gesture == *g));
+					if let Some(binding) = found {
+						println!("gesture {:?}", binding.gesture);
+						tap_mapping(state, &binding.map, oidx);
+					}
 				}
 			}
 		}
@@ -395,6 +1367,11 @@ pub fn state_task(
 	// ff
 	let update_axis = |state: &mut State, axis: Axis, value| {
 		state.axis_states[axis as usize].value = value;
+		if let Some((_, handler)) = state.find_axis_handler(&cached_config, &axis) {
+			if let Some(pad) = &handler.pad {
+				action_sender.send(Action::PadAxis(pad.axis, shape_axis(pad, value))).unwrap();
+			}
+		}
 		if axis == Axis::LeftX || axis == Axis::LeftY {
 			update_stick(
 				state,
@@ -413,9 +1390,107 @@ pub fn state_task(
 		// println!("axis = {:?}", state.axis);
 	};
 
+	// Everything that comes from the controller, or is made up on the control socket.
+	let handle_input = |state: &mut State, ev: InputEvent| match ev {
+		InputEvent::ButtonDown(_, btn) => {
+			maybe_handle_button(state, btn, true);
+		}
+		InputEvent::ButtonUp(_, btn) => {
+			maybe_handle_button(state, btn, false);
+		}
+		InputEvent::AxisMoved(_, axis, value) => {
+			update_axis(state, axis, (value as f64) / 32768.0);
+		}
+		InputEvent::Removed(_) => {
+			release_all(state);
+		}
+		_ => {}
+	};
+
+	let overlay_index = |name: &String| {
+		cached_config.overlays.get_index_of(name).ok_or_else(|| format!("Unknown overlay {}", name))
+	};
+	// Take an overlay off the stack, the topmost one other than the base if none is given.
+	let pop_overlay = |state: &mut State, idx: Option<usize>| -> Result<(), String> {
+		let base = state.base_overlay;
+		if idx == Some(base) {
+			return Err(format!("Can't pop the base overlay {}", cached_config.overlays.get_index(base).unwrap().0));
+		}
+		let pos = match idx {
+			Some(idx) => state.current_overlays.iter().position(|oidx| *oidx == idx),
+			None => Some(state.current_overlays.iter().rposition(|oidx| *oidx != base).ok_or("No overlay to pop")?),
+		};
+		if let Some(pos) = pos {
+			state.current_overlays.remove(pos);
+			release_removed_overlays(state);
+		}
+		Ok(())
+	};
+	let control = |state: &mut State, request: ControlRequest| -> Result<serde_json::Value, String> {
+		match request {
+			ControlRequest::Profile { overlay } => set_base_overlay(state, overlay_index(&overlay)?),
+			ControlRequest::PushOverlay { overlay } => {
+				overlay_index(&overlay)?;
+				add_overlay(state, &overlay);
+			}
+			ControlRequest::PopOverlay { overlay } => {
+				pop_overlay(state, overlay.as_ref().map(overlay_index).transpose()?)?;
+			}
+			ControlRequest::Query => return Ok(state.query()),
+			ControlRequest::Inject { event } => handle_input(state, event),
+			// Needs the whole thread to start over, so it's done in the loop
+			ControlRequest::Reload => unreachable!(),
+			// Taken care of by the control thread
+			ControlRequest::Subscribe => unreachable!(),
+		}
+		Ok(serde_json::Value::Null)
+	};
+
+	if let Some(window) = &focused {
+		focus_changed(&mut state_arc.write().unwrap(), window);
+	}
+
+	// Overlays and sectors are compared with how they were, as lots of things change them
+	let mut published_overlays = Vec::new();
+	let mut published_sectors = vec![None; STICK_AXES.len()];
 	let mut state_sent = false;
 
 	loop {
+		{
+			let mut state = state_arc.write().unwrap();
+			if state.current_overlays != published_overlays {
+				// Until that doesn't change the overlays any further
+				let mut settled = Vec::new();
+				while state.current_overlays != settled {
+					settled = state.current_overlays.clone();
+					release_removed_overlays(&mut state);
+					// Sticks whose handler went away carry on with whatever handler there is now, if any
+					for (stick, (x, y)) in STICK_AXES.iter().enumerate() {
+						if state.stick_states[stick].oidx.is_none() {
+							let (x, y) = (state.axis_states[*x as usize].value, state.axis_states[*y as usize].value);
+							update_stick(&mut state, stick, x, y);
+						}
+					}
+				}
+				// A chord half made when its steno overlay went away doesn't count
+				if state.find_steno(&cached_config).is_none() {
+					state.chord.clear();
+				}
+				published_overlays = state.current_overlays.clone();
+				let name = |oidx: usize| cached_config.overlays.get_index(oidx).unwrap().0.clone();
+				publish(StateEvent::Overlays {
+					overlays: published_overlays.iter().map(|oidx| name(*oidx)).collect(),
+					base_overlay: name(state.base_overlay),
+				});
+			}
+			for (stick, ss) in state.stick_states.iter().enumerate() {
+				if ss.sector != published_sectors[stick] {
+					published_sectors[stick] = ss.sector;
+					publish(StateEvent::Sector { stick, sector: ss.sector });
+				}
+			}
+		}
+
 		if !state_sent {
 			ui_event_proxy.send_event(UIEvent::StateReset(cached_config.clone(), state_arc.clone()))?;
 			state_sent = true;
@@ -427,7 +1502,19 @@ pub fn state_task(
 			let state = state_arc.read().unwrap();
 			// print_state(&state);
 			// println!("{:?}", state.active_maps);
-			state.button_states.iter().filter_map(|bs| bs.handle_at).min()
+			let repeat_at = state.repeating.iter().map(|r| r.next_at);
+			let settle_at = state.stick_states.iter().filter_map(|ss| ss.pending_sector.map(|(_, at)| at));
+			let combo_at = state.pending_combo.map(|(_, at)| at);
+			state
+				.button_states
+				.iter()
+				.filter_map(|bs| bs.handle_at)
+				.chain(repeat_at)
+				.chain(settle_at)
+				.chain(combo_at)
+				.chain(state.sequence_deadline)
+				.chain(state.predictor.as_ref().and_then(Predictor::save_at))
+				.min()
 		};
 
 		let timeout = if let Some(at) = next {
@@ -437,30 +1524,47 @@ pub fn state_task(
 		};
 
 		select! {
-			recv(main_events) -> _ev => {
-				// currently just quit
-				return Ok(());
+			recv(main_events) -> ev => {
+				match ev {
+					Ok(MainEvent::AddOverlay(name)) => add_overlay(&mut state_arc.write().unwrap(), &name),
+					Ok(MainEvent::RemoveOverlay(name)) => remove_overlay(&mut state_arc.write().unwrap(), &name),
+					Ok(MainEvent::Focus(window)) => {
+						focus_changed(&mut state_arc.write().unwrap(), &window);
+						focused = Some(window);
+					}
+					Ok(MainEvent::Control(ControlRequest::Reload, reply)) => {
+						match read_config(config_path).and_then(CachedConfig::new) {
+							Ok(config) => {
+								shut_down(&mut state_arc.write().unwrap());
+								let _ = reply.send(Ok(serde_json::Value::Null));
+								return Ok(StateExit::Reload(Box::new(config), focused));
+							}
+							Err(e) => {
+								let _ = reply.send(Err(format!("{:?}", e)));
+							}
+						}
+					}
+					Ok(MainEvent::Control(request, reply)) => {
+						let _ = reply.send(control(&mut state_arc.write().unwrap(), request));
+					}
+					Ok(ev @ (MainEvent::Typed(_) | MainEvent::Backspace | MainEvent::WordBreak)) => {
+						update_typed(&mut state_arc.write().unwrap(), ev)
+					}
+					Ok(MainEvent::Quit) | Err(_) => {
+						shut_down(&mut state_arc.write().unwrap());
+						return Ok(StateExit::Quit);
+					}
+				}
 			}
 			recv(events) -> ev => {
 				match ev {
 					Ok(ev) => {
 						// println!("{:?}", ev);
-						let mut s = state_arc.write().unwrap();
-						match ev {
-							InputEvent::ButtonDown(_, btn) => {
-								maybe_handle_button(&mut s, btn, true);
-							}
-							InputEvent::ButtonUp(_, btn) => {
-								maybe_handle_button(&mut s, btn, false);
-							}
-							InputEvent::AxisMoved(_, axis, value) => {
-								update_axis(&mut s, axis, (value as f64) / 32768.0);
-							}
-							_ => {}
-						}
+						handle_input(&mut state_arc.write().unwrap(), ev);
 					},
 					Err(_) => {
-						return Ok(());
+						shut_down(&mut state_arc.write().unwrap());
+						return Ok(StateExit::Quit);
 					}
 				}
 			}
@@ -474,7 +1578,160 @@ pub fn state_task(
 					let down = bs.down;
 					do_handle_button(&mut s, btn, down);
 				}
+				if s.sequence_deadline.is_some_and(|at| at <= now) {
+					finish_sequence(&mut s);
+				}
+				if let Some(predictor) = s.predictor.as_mut().filter(|p| p.save_at().is_some_and(|at| at <= now)) {
+					predictor.save();
+				}
+				if let Some((idx, at)) = s.pending_combo {
+					if at <= now {
+						s.pending_combo = None;
+						// No bigger combo came along
+						combo_down(&mut s, idx);
+					}
+				}
+				for (stick, (x, y)) in STICK_AXES.iter().enumerate() {
+					if s.stick_states[stick].pending_sector.is_some_and(|(_, at)| at <= now) {
+						let (x, y) = (s.axis_states[*x as usize].value, s.axis_states[*y as usize].value);
+						update_stick(&mut s, stick, x, y);
+					}
+				}
+				let state = &mut *s;
+				for i in 0..state.repeating.len() {
+					let r = &mut state.repeating[i];
+					if r.next_at > now { continue }
+					r.next_at = now + Duration::from_secs_f64(1. / r.repeat.rate.max(0.1));
+					// Hold off while the overlay the mapping came from is gone
+					if !state.current_overlays.contains(&r.oidx) { continue }
+					let (map, oidx, mode) = (r.map.clone(), r.oidx, r.repeat.mode);
+					match mode {
+						RepeatMode::Autorepeat => {
+							trigger_mapping(state, &map, false, oidx);
+							trigger_mapping(state, &map, true, oidx);
+						}
+						RepeatMode::Turbo => {
+							trigger_mapping(state, &map, true, oidx);
+							trigger_mapping(state, &map, false, oidx);
+						}
+					}
+				}
 			}
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn sequence(buttons: &[Button], timeout: u64) -> CachedSequence {
+		CachedSequence { buttons: buttons.to_vec(), timeout, handler: ButtonHandler::default() }
+	}
+
+	#[test]
+	fn sequence_steps() {
+		use Button::*;
+		let (ab, abc, xy) = (sequence(&[A, B], 500), sequence(&[A, B, X], 800), sequence(&[X, Y], 300));
+		let sequences = [(0, &ab), (0, &abc), (1, &xy)];
+		assert_eq!(sequence_step(&sequences, &[A]), SequenceStep::Pending(800));
+		// A whole sequence still waits for a longer one
+		assert_eq!(sequence_step(&sequences, &[A, B]), SequenceStep::Pending(800));
+		assert_eq!(sequence_step(&sequences, &[A, B, X]), SequenceStep::Complete);
+		assert_eq!(sequence_step(&sequences, &[A, X]), SequenceStep::None);
+		assert_eq!(sequence_step(&sequences, &[X]), SequenceStep::Pending(300));
+		assert_eq!(sequence_step(&sequences, &[X, Y]), SequenceStep::Complete);
+		assert_eq!(sequence_step(&sequences, &[B]), SequenceStep::None);
+		assert_eq!(sequence_step(&[], &[A]), SequenceStep::None);
+	}
+
+	#[test]
+	fn dpad_margin_keeps_held_directions() {
+		let dpad = DpadHandler { diagonal: 30., deadzone: 0.3, release_margin: 10., ..Default::default() };
+		let none = [false; 4];
+		// Left, up, right, down, with left at 0 degrees and up at 90
+		assert_eq!(dpad_directions(&dpad, 45., 0.2, none), none);
+		assert_eq!(dpad_directions(&dpad, 0., 1., none), [true, false, false, false]);
+		assert_eq!(dpad_directions(&dpad, 45., 1., none), [true, true, false, false]);
+		assert_eq!(dpad_directions(&dpad, 25., 1., none), [true, false, false, false]);
+		assert_eq!(dpad_directions(&dpad, 25., 1., [false, true, false, false]), [true, true, false, false]);
+		assert_eq!(dpad_directions(&dpad, 75., 1., [true, true, false, false]), [false, true, false, false]);
+		assert_eq!(dpad_directions(&dpad, 300., 1., none), [true, false, false, true]);
+	}
+
+	#[test]
+	fn dpad_holds_at_most_two_directions() {
+		let dpad = DpadHandler { diagonal: 90., deadzone: 0., release_margin: 180., ..Default::default() };
+		assert_eq!(dpad_directions(&dpad, 20., 1., [true; 4]), [true, true, false, false]);
+		assert_eq!(dpad_directions(&dpad, 200., 1., [true; 4]), [false, false, true, true]);
+	}
+
+	fn templated_config() -> GamepadConfig {
+		json5::from_str(
+			r#"{
+				baseOverlay: "main",
+				templates: {
+					face: {
+						bindings: [{ button: "A", map: { key: "${a}" } }, { button: "B", map: { text: "${a} ${b}" } }],
+					},
+				},
+				overlays: {
+					base: { bindings: [{ button: "A", map: { key: "Space" } }, { button: "X", map: { key: "E" } }] },
+					main: {
+						extends: ["base"],
+						templates: [{ template: "face", args: { a: "C", b: "dee" } }],
+						bindings: [{ button: "Y", map: { key: "U" } }],
+					},
+					one: { extends: ["two"] },
+					two: { extends: ["one"] },
+					self: { extends: ["self"] },
+					missing: { templates: [{ template: "nope" }] },
+				},
+			}"#,
+		)
+		.unwrap()
+	}
+
+	#[test]
+	fn bindings_extend_and_stamp_templates() {
+		let bindings = resolve_bindings(&templated_config(), "main", &mut Vec::new()).unwrap();
+		let maps: Vec<(Button, StateMapping)> = bindings
+			.into_iter()
+			.map(|binding| match binding {
+				Binding::Button { button, handler } => (button, handler.map.unwrap()),
+				other => panic!("{:?}", other),
+			})
+			.collect();
+		// Later ones win, so the template's A comes after the base overlay's
+		assert_eq!(
+			maps,
+			[
+				(Button::A, StateMapping::Key(Key::Space)),
+				(Button::X, StateMapping::Key(Key::E)),
+				(Button::A, StateMapping::Key(Key::C)),
+				(Button::B, StateMapping::Text("C dee".to_string())),
+				(Button::Y, StateMapping::Key(Key::U)),
+			]
+		);
+	}
+
+	#[test]
+	fn bad_extends_and_templates_fail() {
+		let config = templated_config();
+		let error = resolve_bindings(&config, "one", &mut Vec::new()).unwrap_err();
+		assert_eq!(error.to_string(), "Overlays extend each other: one -> two -> one");
+		let error = resolve_bindings(&config, "self", &mut Vec::new()).unwrap_err();
+		assert_eq!(error.to_string(), "Overlay 'self' extends itself");
+		let error = resolve_bindings(&config, "missing", &mut Vec::new()).unwrap_err();
+		assert_eq!(error.to_string(), "Template 'nope' not found");
+		assert!(resolve_bindings(&config, "other", &mut Vec::new()).is_err());
+	}
+
+	#[test]
+	fn args_are_substituted_everywhere() {
+		let args = IndexMap::from([("key".to_string(), "A".to_string()), ("n".to_string(), "2".to_string())]);
+		let value = json!({ "map": { "key": "${key}" }, "label": "${key}${key} ${n} ${other}", "list": ["${n}", 3] });
+		let expected = json!({ "map": { "key": "A" }, "label": "AA 2 ${other}", "list": ["2", 3] });
+		assert_eq!(substitute_args(value, &args), expected);
+	}
+}
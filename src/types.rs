@@ -1,11 +1,55 @@
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use crossbeam_channel::Sender;
 use fs_extra::file::write_all;
 use indexmap::IndexMap;
 use schemars::{JsonSchema, schema_for};
 use serde::{Deserialize, Serialize};
-use strum::{EnumIter, EnumString, IntoStaticStr};
+use strum::{EnumIter, EnumString, IntoEnumIterator, IntoStaticStr};
 
 pub enum MainEvent {
-	Quit
+	Quit,
+	/// Overlay steps of a macro, played on the actions thread
+	AddOverlay(String),
+	RemoveOverlay(String),
+	/// Characters the actions thread typed, for word prediction
+	Typed(String),
+	Backspace,
+	/// A key that moves the cursor or is part of a shortcut, whatever word was being typed is over
+	WordBreak,
+	/// Another window got the focus
+	Focus(FocusedWindow),
+	/// A command from the control socket, and where its result goes
+	Control(ControlRequest, Sender<Result<serde_json::Value, String>>),
+}
+
+/// One line of JSON on the control socket, e.g. `{"command": "pushOverlay", "overlay": "mouse"}`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "camelCase")]
+pub enum ControlRequest {
+	/// Use this base overlay until the focus moves to another window
+	Profile { overlay: String },
+	PushOverlay { overlay: String },
+	/// The topmost overlay other than the base one if none is given, the base overlay itself stays
+	PopOverlay { overlay: Option<String> },
+	/// Held buttons, active overlays and where the sticks are
+	Query,
+	/// Act as if the controller did this
+	Inject { event: InputEvent },
+	/// Read the config file again, the output devices stay as they are
+	Reload,
+	/// Answer like `query`, then send a line for every `StateEvent` until the client goes away or falls too far behind
+	Subscribe,
+}
+
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct FocusedWindow {
+	/// Both halves of WM_CLASS
+	pub instance: String,
+	pub class: String,
+	pub title: String,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize, JsonSchema, EnumIter, EnumString, IntoStaticStr)]
@@ -70,13 +114,99 @@ pub enum InputEvent {
 	Removed(u32),
 }
 
+/// Axes of the virtual gamepad, same as the ABS_* codes
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize, JsonSchema, EnumIter)]
+#[repr(usize)]
+pub enum PadAxis {
+	X = 0,
+	Y,
+	Z,
+	Rx,
+	Ry,
+	Rz,
+	Hat0X,
+	Hat0Y,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub enum Action {
 	KeyDown(Key),
 	KeyUp(Key),
 	AddOverlay(String),
-	RemoveOverlay(String)
+	RemoveOverlay(String),
+	/// Type a string, using the Unicode input method for anything the keymap can't produce
+	Text(String),
+	CharDown(char),
+	CharUp(char),
+	/// Run a command
+	Exec(ExecMapping),
+	/// Move a virtual gamepad axis, -1.0 to 1.0 (0.0 to 1.0 for triggers). Not available in configs.
+	#[serde(skip)]
+	PadAxis(PadAxis, f64),
+	/// Move the absolute pointer, 0.0 to 1.0 of the screen on each axis. Not available in configs.
+	#[serde(skip)]
+	PointerMove(f64, f64),
+	/// Release every key the virtual device is holding. Not available in configs.
+	#[serde(skip)]
+	ReleaseAll,
+	/// Start playing macro steps under an id. Not available in configs.
+	#[serde(skip)]
+	Macro(u64, Vec<MacroStep>),
+	/// Stop the macro with this id and let go of whatever it holds. Not available in configs.
+	#[serde(skip)]
+	CancelMacro(u64),
+	/// Play steps in turn with text, after whatever is still being typed. Not available in configs.
+	#[serde(skip)]
+	Type(Vec<MacroStep>),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum MacroStep {
+	KeyDown(Key),
+	KeyUp(Key),
+	/// Press and release
+	Tap(Key),
+	Text(String),
+	/// Wait this many milliseconds
+	Delay(u64),
+	AddOverlay(String),
+	RemoveOverlay(String),
+	Exec(ExecMapping),
+	/// A key event of typed text, not counted as held
+	#[serde(skip)]
+	Emit(Key, bool),
+	/// Press again the modifiers that typed text let go of, those that are still held
+	#[serde(skip)]
+	RestoreModifiers,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecMapping {
+	pub command: String,
+	#[serde(default)]
+	pub args: Vec<String>,
+	/// Added to the environment tamepad was started with
+	#[serde(default)]
+	pub env: IndexMap<String, String>,
+	/// In a macro, hold off the steps after it until the command exits, instead of leaving it running on its own
+	#[serde(default)]
+	pub wait: bool,
+	/// Ignore runs less than this many milliseconds after the previous one
+	#[serde(default)]
+	pub debounce: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MacroMapping {
+	pub steps: Vec<MacroStep>,
+	/// Stop playing when the binding is released
+	#[serde(default)]
+	pub cancel_on_release: bool,
+	pub label: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
@@ -84,6 +214,38 @@ pub enum Action {
 pub enum StateMapping {
 	Key(Key),
 	Overlay(String),
+	/// Type a string when pressed
+	Text(String),
+	/// Hold whatever key and modifiers produce this character in the configured keymap
+	Char(char),
+	/// Hold the modifiers, then the key, and let go in reverse
+	Chord { modifiers: Vec<Key>, key: Key },
+	/// Play steps one after another, keys still held at the end are released
+	Macro(MacroMapping),
+	/// Run a command when pressed
+	Exec(ExecMapping),
+	/// Type the rest of this word prediction suggestion, 0 is the best one
+	Suggestion(usize),
+}
+
+impl StateMapping {
+	pub fn label(&self) -> String {
+		match self {
+			StateMapping::Key(key) => key.into_static_str().to_string(),
+			StateMapping::Overlay(name) => name.clone(),
+			StateMapping::Text(text) => text.clone(),
+			StateMapping::Char(c) => c.to_string(),
+			StateMapping::Chord { modifiers, key } => modifiers
+				.iter()
+				.chain([key])
+				.map(|key| key.into_static_str())
+				.collect::<Vec<_>>()
+				.join("+"),
+			StateMapping::Macro(mapping) => mapping.label.clone().unwrap_or_else(|| "Macro".to_string()),
+			StateMapping::Exec(exec) => exec.command.rsplit('/').next().unwrap_or_default().to_string(),
+			StateMapping::Suggestion(idx) => format!("Suggestion {}", idx + 1),
+		}
+	}
 }
 
 fn default_zero() -> f32 { 0. }
@@ -122,27 +284,115 @@ pub struct StickConfig {
 	pub point: ButtonDrawConfig,
 }
 
+#[derive(Default, Copy, Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ComboRelease {
+	/// Let go of the combo as soon as one of its buttons is released
+	#[default]
+	Any,
+	/// Hold the combo until all of its buttons are released
+	All,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct ButtonCombo {
 	pub timeout: u64,
 	pub buttons: Vec<Button>,
+	/// When several combos are down at once the highest priority wins, then the one with more buttons.
+	/// A combo waits up to the `timeout` of bigger combos with at least its priority, in case they're being pressed.
+	#[serde(default)]
+	pub priority: i32,
+	/// Only count the combo when its buttons are pressed in the order they're listed
+	#[serde(default)]
+	pub strict_order: bool,
+	#[serde(default)]
+	pub release_mode: ComboRelease,
 	#[serde(flatten)]
 	pub draw: ButtonDrawConfig,
 }
 
+#[derive(Default, Copy, Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum RepeatMode {
+	/// Hold the mapping and press it again at the repeat rate, like a keyboard does
+	#[default]
+	Autorepeat,
+	/// Press and release the mapping right away, then again at the repeat rate
+	Turbo,
+}
+
+fn default_repeat_delay() -> u64 { 500 }
+fn default_repeat_rate() -> f64 { 25. }
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RepeatConfig {
+	#[serde(default)]
+	pub mode: RepeatMode,
+	/// Milliseconds before the first repeat
+	#[serde(default = "default_repeat_delay")]
+	pub delay: u64,
+	/// Repeats per second
+	#[serde(default = "default_repeat_rate")]
+	pub rate: f64,
+}
+
 #[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct ButtonHandler {
 	pub map: Option<StateMapping>,
 	pub down: Option<Action>,
 	pub up: Option<Action>,
-	pub label: Option<String>
+	pub label: Option<String>,
+	/// Keep pressing the key while the binding is held, other mappings don't repeat
+	pub repeat: Option<RepeatConfig>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct CircleHandler {
+	#[serde(default)]
+	pub min_value: f64,
+	#[serde(default)]
+	pub sectors: Vec<CircleSector>,
+	/// More rings with their own sectors, `minValue` and `sectors` are one more ring
+	#[serde(default)]
+	pub rings: Vec<CircleRing>,
+	/// Degrees the stick has to go past a sector's edge before leaving it
+	#[serde(default)]
+	pub hysteresis_degrees: f64,
+	/// How far past the edges of its ring the stick has to go before leaving a sector
+	#[serde(default)]
+	pub hysteresis_value: f64,
+	/// Milliseconds the stick has to stay in a new sector before switching to it
+	#[serde(default)]
+	pub settle_ms: u64,
+	/// Type words by sweeping through the sectors' `letters`, instead of triggering their mappings
+	pub swipe: Option<SwipeConfig>,
+}
+
+fn default_alternates() -> usize { 4 }
+fn default_suffix() -> String { " ".to_string() }
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SwipeConfig {
+	/// Word list, one word per line optionally followed by how often it's used
+	pub dictionary: String,
+	/// How many other words to offer besides the one typed, picked with the other stick
+	#[serde(default = "default_alternates")]
+	pub alternates: usize,
+	/// Typed after each word
+	#[serde(default = "default_suffix")]
+	pub suffix: String,
+}
+
+/// Sectors for stick values from `minValue` up to where the next ring starts.
+#[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CircleRing {
 	pub min_value: f64,
-	pub sectors: Vec<CircleSector>
+	pub sectors: Vec<CircleSector>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
@@ -153,12 +403,151 @@ pub struct CircleSector {
 	pub enter: Option<Action>,
 	pub exit: Option<Action>,
 	pub label: Option<String>,
+	/// Keep pressing the key while the stick stays in the sector, other mappings don't repeat
+	pub repeat: Option<RepeatConfig>,
+	/// Letters on this sector for swipe typing
+	pub letters: Option<String>,
+}
+
+fn default_one() -> f64 { 1. }
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PadAxisMapping {
+	pub axis: PadAxis,
+	/// Values closer to the center than this are reported as centered
+	#[serde(default)]
+	pub deadzone: f64,
+	/// Exponent applied to the value left after the deadzone, 1 is linear
+	#[serde(default = "default_one")]
+	pub curve: f64,
+	#[serde(default)]
+	pub invert: bool,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AxisHandler {
+	/// Forward the axis to the virtual gamepad
+	pub pad: Option<PadAxisMapping>,
+}
+
+/// Part of the screen, 0.0 to 1.0 on each axis
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ScreenRegion {
+	#[serde(default)]
+	pub x: f64,
+	#[serde(default)]
+	pub y: f64,
+	#[serde(default = "default_one")]
+	pub width: f64,
+	#[serde(default = "default_one")]
+	pub height: f64,
+}
+
+impl Default for ScreenRegion {
+	fn default() -> Self {
+		Self { x: 0., y: 0., width: 1., height: 1. }
+	}
+}
+
+/// Put the absolute pointer where the stick points: centered is the middle of the region, full tilt is its edge.
+#[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AbsolutePointerHandler {
+	#[serde(default)]
+	pub region: ScreenRegion,
+	/// Stick values below this are treated as centered
+	#[serde(default)]
+	pub deadzone: f64,
+}
+
+fn default_diagonal() -> f64 { 45. }
+fn default_dpad_deadzone() -> f64 { 0.3 }
+
+/// Press the mappings of up to two neighbouring directions at once, like a d-pad.
+#[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DpadHandler {
+	pub up: Option<StateMapping>,
+	pub down: Option<StateMapping>,
+	pub left: Option<StateMapping>,
+	pub right: Option<StateMapping>,
+	/// Degrees around each diagonal where both directions are held, 0 to 90
+	#[serde(default = "default_diagonal")]
+	pub diagonal: f64,
+	/// Stick values below this press nothing
+	#[serde(default = "default_dpad_deadzone")]
+	pub deadzone: f64,
+	/// Degrees a held direction's zone grows by, so it doesn't flicker at the edge
+	#[serde(default)]
+	pub release_margin: f64,
+}
+
+/// Eight ways to point a stick, in the same order as the angles, starting at 0 degrees.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize, Deserialize, JsonSchema, EnumIter)]
+#[serde(rename_all = "camelCase")]
+pub enum StickDirection {
+	Left,
+	UpLeft,
+	Up,
+	UpRight,
+	Right,
+	DownRight,
+	Down,
+	DownLeft,
+}
+
+impl StickDirection {
+	pub fn from_degrees(degrees: f64) -> Self {
+		let idx = ((degrees + 22.5) / 45.).floor().rem_euclid(8.) as usize;
+		Self::iter().nth(idx).unwrap()
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Gesture {
+	/// Out in one direction and straight back, quicker than `flickMs`
+	Flick(StickDirection),
+	/// This many full spins
+	Clockwise(u32),
+	CounterClockwise(u32),
+	/// Through these directions in order
+	Path(Vec<StickDirection>),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GestureBinding {
+	pub gesture: Gesture,
+	/// Pressed and released when the gesture is done
+	pub map: StateMapping,
+	pub label: Option<String>,
+}
+
+fn default_gesture_threshold() -> f64 { 0.5 }
+fn default_flick_ms() -> u64 { 250 }
+
+/// Recognize gestures drawn with the stick. They start when the stick goes past `threshold` and end when it's back.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GestureHandler {
+	#[serde(default = "default_gesture_threshold")]
+	pub threshold: f64,
+	#[serde(default = "default_flick_ms")]
+	pub flick_ms: u64,
+	pub gestures: Vec<GestureBinding>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct StickHandler {
-	pub circle: Option<CircleHandler>
+	pub circle: Option<CircleHandler>,
+	pub absolute: Option<AbsolutePointerHandler>,
+	pub dpad: Option<DpadHandler>,
+	pub gestures: Option<GestureHandler>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
@@ -177,25 +566,231 @@ pub enum Binding {
 	Stick {
 		stick: Stick,
 		#[serde(flatten)]
-		handler: StickHandler
+		handler: Box<StickHandler>
+	},
+	Axis {
+		axis: Axis,
+		#[serde(flatten)]
+		handler: AxisHandler
+	},
+	/// Buttons pressed one after another, each within `timeout` ms of the last. If they stop short or go another
+	/// way, the buttons pressed so far do what they are bound to on their own.
+	Sequence {
+		sequence: Vec<Button>,
+		#[serde(default = "default_sequence_timeout")]
+		timeout: u64,
+		#[serde(flatten)]
+		handler: ButtonHandler
 	},
-	Axis { axis: Axis },
 }
 
+fn default_sequence_timeout() -> u64 { 1000 }
+
 #[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct Overlay {
 	pub label: Option<String>,
+	/// Overlays whose bindings this one starts out with
+	#[serde(default)]
+	pub extends: Vec<String>,
+	/// Templates stamped out before this overlay's own bindings
+	#[serde(default)]
+	pub templates: Vec<TemplateUse>,
+	#[serde(default)]
 	pub bindings: Vec<Binding>,
+	/// Collect buttons into chords while this overlay is on top
+	pub steno: Option<StenoConfig>,
+	/// Adding this overlay removes the others in the same group
+	pub group: Option<String>,
+	/// Overlays with a higher priority stay above it, however recently it was added
+	#[serde(default)]
+	pub priority: i32,
+	/// Don't look in the overlays below for anything this one doesn't bind
+	#[serde(default)]
+	pub opaque: bool,
+}
+
+/// Buttons pressed together make a chord, which is looked up and typed once they're all released.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StenoConfig {
+	/// One chord per line: buttons joined with `+`, then what to type, e.g. `A+X+LeftShoulder hello`
+	pub dictionary: String,
+	/// The buttons that make chords, all of them when not set. The rest keep their bindings.
+	pub buttons: Option<Vec<Button>>,
+	/// Typed after each chord's text
+	#[serde(default = "default_suffix")]
+	pub suffix: String,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize, Deserialize, JsonSchema)]
+pub enum BusType {
+	Usb,
+	Bluetooth,
+	Virtual,
+	Host,
+	I8042,
+}
+
+/// How a virtual device introduces itself. Anything not set falls back to the device's defaults.
+#[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceConfig {
+	pub name: Option<String>,
+	pub vendor: Option<u16>,
+	pub product: Option<u16>,
+	pub version: Option<u16>,
+	pub bus: Option<BusType>,
+	/// Keys the device claims to have, every known key if not set
+	pub keys: Option<Vec<Key>>,
+}
+
+/// How to type characters the keymap has no key for
+#[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum UnicodeInput {
+	/// Skip them
+	None,
+	/// Ctrl+Shift+U, the hex code, then Space, as understood by GTK and IBus
+	#[default]
+	CtrlShiftU,
+	/// Press the `start` keys together, type the hex code, then press `end`
+	Custom { start: Vec<Key>, end: Key },
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TextConfig {
+	#[serde(default)]
+	pub unicode: UnicodeInput,
+	/// XKB keymap file describing the layout the compositor uses, US QWERTY if not set
+	pub keymap: Option<String>,
+	/// Milliseconds to wait after each key event while typing text, for clients that drop keys sent all at once
+	#[serde(default)]
+	pub key_delay: u64,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputConfig {
+	#[serde(default)]
+	pub keyboard: DeviceConfig,
+	/// Create a virtual gamepad too. Gamepad buttons (ButtonSouth etc.) mapped as keys are sent to it.
+	pub gamepad: Option<DeviceConfig>,
+	/// Create a virtual pen tablet covering the screen, driven by `absolute` stick handlers.
+	/// ButtonTouch, ButtonStylus and ButtonStylus2 mapped as keys are sent to it.
+	pub pointer: Option<DeviceConfig>,
+	#[serde(default)]
+	pub text: TextConfig,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct GamepadConfig {
+	#[serde(default)]
 	pub buttons: Vec<ButtonConfig>,
+	#[serde(default)]
 	pub combos: IndexMap<String, ButtonCombo>,
+	#[serde(default)]
 	pub sticks: Vec<StickConfig>,
 	pub overlays: IndexMap<String, Overlay>,
+	/// Used when no focus rule matches
 	pub base_overlay: String,
+	pub focus: Option<FocusConfig>,
+	/// Where to listen for commands, one line of JSON each, see `ControlRequest`
+	pub control_socket: Option<String>,
+	#[serde(default)]
+	pub output: OutputConfig,
+	pub prediction: Option<PredictionConfig>,
+	#[serde(default)]
+	pub templates: IndexMap<String, Template>,
+	/// Config files merged in before this one, relative to it. Objects like `overlays` are merged by key,
+	/// lists at the top like `buttons` are added to, and anything else set here wins, lists further in too.
+	#[serde(default)]
+	pub include: Vec<String>,
+}
+
+/// Bindings to stamp into overlays, where `${name}` in any string is replaced by the argument of that name.
+#[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Template {
+	pub bindings: Vec<serde_json::Value>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct TemplateUse {
+	pub template: String,
+	#[serde(default)]
+	pub args: IndexMap<String, String>,
+}
+
+fn default_focus_interval() -> u64 { 500 }
+
+/// How to find out which window has the focus.
+#[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum FocusBackend {
+	/// Follow _NET_ACTIVE_WINDOW on the X server in $DISPLAY
+	#[default]
+	X11,
+	/// Run a command every `interval` ms, it prints the window's class on the first line and its title on the second
+	Command {
+		command: String,
+		#[serde(default)]
+		args: Vec<String>,
+		#[serde(default = "default_focus_interval")]
+		interval: u64,
+	},
+}
+
+/// Switch the base overlay depending on the focused window.
+#[derive(Default, Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FocusConfig {
+	#[serde(default)]
+	pub backend: FocusBackend,
+	/// The first rule that matches wins
+	pub rules: Vec<FocusRule>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FocusRule {
+	/// Either half of WM_CLASS, ignoring case
+	pub class: Option<String>,
+	/// Part of the window title
+	pub title: Option<String>,
+	pub overlay: String,
+}
+
+impl FocusRule {
+	pub fn matches(&self, window: &FocusedWindow) -> bool {
+		let class = self.class.as_ref().is_none_or(|class| {
+			class.eq_ignore_ascii_case(&window.class) || class.eq_ignore_ascii_case(&window.instance)
+		});
+		let title = self.title.as_ref().is_none_or(|title| window.title.contains(title.as_str()));
+		class && title
+	}
+}
+
+fn default_suggestions() -> usize { 3 }
+
+/// Suggest completions for the word being typed.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PredictionConfig {
+	/// Word list, one word per line optionally followed by how often it's used
+	pub dictionary: Option<String>,
+	/// Where words learned from typing are kept, in the same format
+	pub user_dictionary: Option<String>,
+	#[serde(default = "default_suggestions")]
+	pub suggestions: usize,
+	/// Typed after an accepted suggestion
+	#[serde(default = "default_suffix")]
+	pub suffix: String,
+	/// Where the suggestions are shown
+	#[serde(default = "default_zero")]
+	pub x: f32,
+	#[serde(default = "default_zero")]
+	pub y: f32,
 }
 
 /// Copied from input-linux, mapped to it by name
@@ -967,6 +1562,75 @@ macro_rules! impl_static_str {
 impl_static_str!(Button);
 impl_static_str!(Key);
 
+pub fn read_config(path: &str) -> Result<GamepadConfig, anyhow::Error> {
+	let config = read_config_value(Path::new(path), &mut Vec::new())?;
+	// Back through json5 rather than serde_json::from_value, which won't take e.g. `300.0` for a u64
+	json5::from_str(&config.to_string()).map_err(|e| anyhow!("{}: {}", path, e))
+}
+
+/// Read a config file with the files it includes merged in, relative to it.
+fn read_config_value(path: &Path, seen: &mut Vec<PathBuf>) -> Result<serde_json::Value, anyhow::Error> {
+	let canonical = path.canonicalize().map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+	if seen.contains(&canonical) {
+		bail!("{} includes itself", path.display());
+	}
+	let mut config: serde_json::Value =
+		json5::from_str(&read_to_string(path)?).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+	let includes: Vec<String> = match config.as_object_mut().and_then(|c| c.remove("include")) {
+		Some(includes) => serde_json::from_value(includes).map_err(|e| anyhow!("{}: include: {}", path.display(), e))?,
+		None => Vec::new(),
+	};
+
+	seen.push(canonical);
+	let dir = path.parent().unwrap_or(Path::new(""));
+	let mut merged = serde_json::Value::Object(Default::default());
+	for include in includes {
+		merge_config(&mut merged, read_config_value(&dir.join(include), seen)?);
+	}
+	seen.pop();
+
+	merge_config(&mut merged, config);
+	Ok(merged)
+}
+
+/// Objects are merged key by key and anything else is replaced, except that the lists of bindings at the top
+/// are appended to.
+fn merge_config(base: &mut serde_json::Value, other: serde_json::Value) {
+	use serde_json::Value;
+	match (base, other) {
+		(Value::Object(base), Value::Object(other)) => {
+			for (key, value) in other {
+				match (base.get_mut(&key), value) {
+					(Some(Value::Array(existing)), Value::Array(value)) => existing.extend(value),
+					(Some(existing), value) => merge_value(existing, value),
+					(None, value) => {
+						base.insert(key, value);
+					}
+				}
+			}
+		}
+		(base, other) => *base = other,
+	}
+}
+
+/// Objects are merged key by key, anything else is replaced.
+fn merge_value(base: &mut serde_json::Value, other: serde_json::Value) {
+	use serde_json::Value;
+	match (base, other) {
+		(Value::Object(base), Value::Object(other)) => {
+			for (key, value) in other {
+				match base.get_mut(&key) {
+					Some(existing) => merge_value(existing, value),
+					None => {
+						base.insert(key, value);
+					}
+				}
+			}
+		}
+		(base, other) => *base = other,
+	}
+}
+
 pub fn write_schema() {
 	
 	let schema = schema_for!(GamepadConfig);
@@ -975,3 +1639,71 @@ pub fn write_schema() {
 	write_all("configs/schema.json", &str).unwrap();
 	
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	#[test]
+	fn merge_appends_top_level_lists() {
+		let mut base = json!({ "buttons": [{ "button": "A" }], "baseOverlay": "main" });
+		merge_config(&mut base, json!({ "buttons": [{ "button": "B" }], "baseOverlay": "game" }));
+		assert_eq!(base, json!({ "buttons": [{ "button": "A" }, { "button": "B" }], "baseOverlay": "game" }));
+	}
+
+	#[test]
+	fn merge_replaces_nested_lists() {
+		let mut base = json!({ "overlays": { "main": { "label": "Main", "bindings": [1, 2] }, "other": {} } });
+		merge_config(&mut base, json!({ "overlays": { "main": { "bindings": [3] } } }));
+		assert_eq!(base, json!({ "overlays": { "main": { "label": "Main", "bindings": [3] }, "other": {} } }));
+	}
+
+	#[test]
+	fn merge_adds_missing_keys() {
+		let mut base = json!({});
+		merge_config(&mut base, json!({ "sticks": [], "output": { "text": { "keyDelay": 5 } } }));
+		assert_eq!(base, json!({ "sticks": [], "output": { "text": { "keyDelay": 5 } } }));
+	}
+
+	#[test]
+	fn read_config_merges_includes() {
+		let dir = std::env::temp_dir().join(format!("tamepad-include-{}", std::process::id()));
+		std::fs::create_dir_all(dir.join("controllers")).unwrap();
+		std::fs::write(
+			dir.join("controllers/pad.json5"),
+			"{ combos: { both: { buttons: ['A', 'B'], timeout: 300.0 } }, overlays: { main: {} }, baseOverlay: 'main' }",
+		)
+		.unwrap();
+		std::fs::write(
+			dir.join("config.json5"),
+			"{ include: ['controllers/pad.json5'], baseOverlay: 'game', overlays: { game: {} } }",
+		)
+		.unwrap();
+		let config = read_config(dir.join("config.json5").to_str().unwrap());
+		std::fs::remove_dir_all(&dir).unwrap();
+		let config = config.unwrap();
+		assert_eq!(config.base_overlay, "game");
+		assert_eq!(config.overlays.keys().collect::<Vec<_>>(), ["main", "game"]);
+		assert_eq!(config.combos["both"].timeout, 300);
+	}
+
+	#[test]
+	fn focus_rule_matches() {
+		let window =
+			FocusedWindow { instance: "navigator".into(), class: "Firefox".into(), title: "Inbox - Mail".into() };
+		let rule = |class: Option<&str>, title: Option<&str>| FocusRule {
+			class: class.map(String::from),
+			title: title.map(String::from),
+			overlay: "main".into(),
+		};
+		assert!(rule(None, None).matches(&window));
+		assert!(rule(Some("firefox"), None).matches(&window));
+		assert!(rule(Some("Navigator"), None).matches(&window));
+		assert!(!rule(Some("fire"), None).matches(&window));
+		assert!(rule(None, Some("Inbox")).matches(&window));
+		assert!(!rule(None, Some("inbox")).matches(&window));
+		assert!(rule(Some("firefox"), Some("Mail")).matches(&window));
+		assert!(!rule(Some("xterm"), Some("Mail")).matches(&window));
+	}
+}